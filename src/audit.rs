@@ -0,0 +1,63 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::template;
+
+/// --audit-log: 完了したダウンロードを 1 行 1 件の JSON で追記する記録
+///
+/// 各行は `{"timestamp":"2026-10-14T12:34:56Z","url":"...","path":"...","size":123,"sha256":"..."}` の形。
+/// 途中で異常終了しても記録が失われないよう、1 件ごとにファイルへ書き出す。
+pub struct AuditLog {
+    file: File,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file })
+    }
+
+    pub fn record(
+        &mut self,
+        url: &str,
+        path: &Path,
+        size: u64,
+        sha256: Option<&str>,
+    ) -> io::Result<()> {
+        let sha256 = match sha256 {
+            Some(sha256) => json_string(sha256),
+            None => "null".to_string(),
+        };
+        let line = format!(
+            "{{\"timestamp\":{},\"url\":{},\"path\":{},\"size\":{},\"sha256\":{}}}\n",
+            json_string(&template::timestamp()),
+            json_string(url),
+            json_string(&path.to_string_lossy()),
+            size,
+            sha256
+        );
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
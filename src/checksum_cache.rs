@@ -0,0 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use sha2::{Digest, Sha256};
+
+/// --checksum-cache: ファイルごとに `(更新日時, サイズ) -> SHA-256` を覚えておく
+///
+/// 1 行に `<sha256>\t<サイズ>\t<更新日時 (エポックからのナノ秒)>\t<絶対パス>` を書く。
+/// 更新日時かサイズが変わったファイルは計算し直す。
+pub struct ChecksumCache {
+    path: PathBuf,
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    mtime: u128,
+    sha256: String,
+}
+
+impl ChecksumCache {
+    /// キャッシュを読み込む。まだ無ければ空で始める。読めない行は無視する
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let entries = text
+            .lines()
+            .filter_map(|line| {
+                let mut fields = line.splitn(4, '\t');
+                let sha256 = fields.next()?.to_string();
+                let size = fields.next()?.parse().ok()?;
+                let mtime = fields.next()?.parse().ok()?;
+                let file = PathBuf::from(fields.next()?);
+                Some((
+                    file,
+                    Entry {
+                        size,
+                        mtime,
+                        sha256,
+                    },
+                ))
+            })
+            .collect();
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// `file` の SHA-256。前回から変わっていなければ読み直さずにキャッシュの値を返す
+    pub fn sha256(&mut self, file: &Path) -> io::Result<String> {
+        let file = std::path::absolute(file)?;
+        let (size, mtime) = stat(&file)?;
+        if let Some(entry) = self.entries.get(&file) {
+            if entry.size == size && entry.mtime == mtime {
+                return Ok(entry.sha256.clone());
+            }
+        }
+        let mut reader = File::open(&file)?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        let sha256: String = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        self.entries.insert(
+            file,
+            Entry {
+                size,
+                mtime,
+                sha256: sha256.clone(),
+            },
+        );
+        Ok(sha256)
+    }
+
+    /// ダウンロードしながら計算したハッシュを、書き終えた `file` のものとして記録する
+    pub fn record(&mut self, file: &Path, sha256: &str) -> io::Result<()> {
+        let file = std::path::absolute(file)?;
+        let (size, mtime) = stat(&file)?;
+        self.entries.insert(
+            file,
+            Entry {
+                size,
+                mtime,
+                sha256: sha256.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let text: String = self
+            .entries
+            .iter()
+            .map(|(file, entry)| {
+                format!(
+                    "{}\t{}\t{}\t{}\n",
+                    entry.sha256,
+                    entry.size,
+                    entry.mtime,
+                    file.display()
+                )
+            })
+            .collect();
+        fs::write(&self.path, text)
+    }
+}
+
+fn stat(file: &Path) -> io::Result<(u64, u128)> {
+    let metadata = fs::metadata(file)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0);
+    Ok((metadata.len(), mtime))
+}
@@ -0,0 +1,537 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use reqwest::header::{HeaderName, HeaderValue};
+use reqwest::{Method, StatusCode};
+
+use crate::expand::{expand_path, expand_vars};
+use crate::template::OutputTemplate;
+
+/// 小さなコマンドラインツールの詰め合わせ。サブコマンドを省略すると download として動く
+#[derive(Debug, Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// サブコマンドを省略したときの download の引数
+    #[command(flatten)]
+    pub download: DownloadArgs,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// 複数ファイルを並行ダウンロードし、進捗をターミナルに表示する
+    Download(DownloadArgs),
+}
+
+/// `download` サブコマンドの引数
+#[derive(Debug, Parser)]
+pub struct DownloadArgs {
+    /// ダウンロードする URL（省略時はサンプルをダウンロード）。`$VAR` を展開する
+    #[arg(value_name = "URL", value_parser = expand_vars)]
+    pub urls: Vec<String>,
+
+    /// クリップボードの URL（改行区切りで複数可）をダウンロード対象に加える
+    #[arg(long)]
+    pub from_clipboard: bool,
+
+    /// 保存先ディレクトリ。`~` と `$VAR` を展開する
+    #[arg(short = 'd', long, value_name = "DIR", value_parser = parse_path)]
+    pub output_dir: Option<PathBuf>,
+
+    /// 保存先ディレクトリの下に URL のホスト名ごとのディレクトリを作って保存する
+    #[arg(long, conflicts_with = "concat")]
+    pub organize_by_host: bool,
+
+    /// 保存先のパスのテンプレート（例: "{host}/{date}/{name}"）。{host} {date} {index} {name} が使える
+    #[arg(long, value_name = "TEMPLATE", value_parser = OutputTemplate::parse, conflicts_with_all = ["concat", "organize_by_host"])]
+    pub output_template: Option<OutputTemplate>,
+
+    /// 保存先が重なった URL のファイル名に " (1)", " (2)" ... を付けて区別する
+    #[arg(long, conflicts_with = "no_dedupe")]
+    pub dedupe_names: bool,
+
+    /// 保存先が重なった URL があればエラーにする（既定では後のダウンロードで上書きする）
+    #[arg(long)]
+    pub no_dedupe: bool,
+
+    /// HTTP メソッド（既定は GET、--data / --data-file 指定時は POST）
+    #[arg(short = 'X', long, value_name = "METHOD", value_parser = parse_method)]
+    pub method: Option<Method>,
+
+    /// リクエスト本文（既定の Content-Type は application/json）
+    #[arg(long, value_name = "BODY", conflicts_with = "data_file")]
+    pub data: Option<String>,
+
+    /// リクエスト本文を読み込むファイル
+    #[arg(long, value_name = "FILE", value_parser = parse_path)]
+    pub data_file: Option<PathBuf>,
+
+    /// リクエストに付けるクッキー（"name=value" 形式、複数指定可）
+    #[arg(long = "cookie", value_name = "NAME=VALUE", value_parser = parse_cookie)]
+    pub cookies: Vec<String>,
+
+    /// クッキーを読み込み、終了時に保存する JSON ファイル
+    #[arg(long, value_name = "FILE", value_parser = parse_path)]
+    pub cookie_jar: Option<PathBuf>,
+
+    /// 指定した URL を順番に取得し、--output の 1 ファイルに連結して保存する（GET で 1 回だけ試行する）
+    #[arg(long, requires_all = ["output", "urls"], conflicts_with_all = ["method", "data", "data_file", "tries"])]
+    pub concat: bool,
+
+    /// --concat の出力ファイル名（保存先ディレクトリからの相対パス）
+    #[arg(short, long, value_name = "FILE", requires = "concat", value_parser = parse_path)]
+    pub output: Option<PathBuf>,
+
+    /// ブロックハッシュのマニフェストの URL。保存先にある旧版と比べ、変わったブロックだけを取得する（URL は 1 つだけ指定する）
+    #[arg(long, value_name = "MANIFEST_URL", value_parser = expand_vars, requires = "urls", conflicts_with = "concat")]
+    pub zsync: Option<String>,
+
+    /// 保存先にあるファイルをブロックハッシュのマニフェスト（URL またはファイル）と同じ位置ごとに照合し、
+    /// 壊れたブロックだけを Range で取得し直して修復する（URL は 1 つだけ指定する）
+    #[arg(long, value_name = "MANIFEST", value_parser = expand_vars, requires = "urls", conflicts_with_all = ["concat", "zsync"])]
+    pub repair: Option<String>,
+
+    /// TCP の代わりにこの Unix ドメインソケットへ接続する（URL のホスト名は Host ヘッダーにだけ使われる）
+    #[arg(long, value_name = "PATH", value_parser = parse_path)]
+    pub unix_socket: Option<PathBuf>,
+
+    /// リクエストに付ける Referer（リンク元のページの URL）。--header での指定が優先される
+    #[arg(long, value_name = "URL", value_parser = parse_referer)]
+    pub referer: Option<HeaderValue>,
+
+    /// リクエストに付ける Origin。URL のスキーム・ホスト・ポートだけを使う。--header での指定が優先される
+    #[arg(long, value_name = "URL", value_parser = parse_origin)]
+    pub origin: Option<HeaderValue>,
+
+    /// ホスト名の名前解決を上書きする（curl と同じ "host:port:addr" 形式、複数指定可）。
+    /// 接続先のポートは URL のものが使われる
+    #[arg(long = "resolve", value_name = "HOST:PORT:ADDR", value_parser = parse_resolve)]
+    pub resolves: Vec<(String, SocketAddr)>,
+
+    /// ALPN で交渉せず、最初から HTTP/2 で接続する（多数のファイルを同じホストから取るときに 1 本の接続へ多重化できる）
+    #[arg(long)]
+    pub http2_prior_knowledge: bool,
+
+    /// HTTP/3 (QUIC) で接続する。http3 feature を有効にしてビルドした場合だけ使える
+    #[arg(long, conflicts_with_all = ["http2_prior_knowledge", "unix_socket"])]
+    pub http3: bool,
+
+    /// ホストごとに残しておくアイドル接続の上限（0 で接続を使い回さない）
+    #[arg(long, value_name = "N")]
+    pub pool_max_idle_per_host: Option<usize>,
+
+    /// アイドル接続を閉じるまでの秒数（既定は 90 秒）
+    #[arg(long, value_name = "SECS", value_parser = parse_positive_secs)]
+    pub pool_idle_timeout: Option<f64>,
+
+    /// 追加のリクエストヘッダー（"Name: value" 形式、複数指定可）。値の `$VAR` を展開する
+    #[arg(short = 'H', long = "header", value_name = "HEADER", value_parser = parse_header)]
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// 保存先ディレクトリの `<name>.part` を探し、対応する URL を Range リクエストで再開する
+    #[arg(long)]
+    pub resume_all: bool,
+
+    /// 指定したファイル名のダウンロードだけを、保存先の `<name>.part` から Range リクエストで再開する（複数指定可）
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["resume_all", "concat", "zsync", "repair"])]
+    pub resume_only: Vec<String>,
+
+    /// URL ごとのダウンロードの状態を組み込みの KV ストアに記録し、次回は完了済みを飛ばして未完了を `.part` から再開する
+    #[arg(long, value_name = "PATH", value_parser = parse_path, conflicts_with_all = ["concat", "zsync", "repair", "decompress"])]
+    pub state_db: Option<PathBuf>,
+
+    /// レスポンスの Last-Modified をダウンロードしたファイルの更新日時に設定する
+    #[arg(long)]
+    pub use_server_timestamps: bool,
+
+    /// 同時にダウンロードする数（実行中に +/- キーで変更できる）
+    #[arg(short, long, value_name = "N", default_value_t = 4, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub jobs: usize,
+
+    /// 同じホストから同時にダウンロードする数の上限（0 で無制限、実行中に s キーの設定パネルで変更できる）
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub max_per_host: usize,
+
+    /// 本文を受け取る前に、すべての URL へ HEAD を送って全体のサイズを調べる（HEAD を拒むサーバーでは GET で分かるのを待つ）
+    #[arg(long, conflicts_with_all = ["concat", "zsync", "repair"])]
+    pub preflight: bool,
+
+    /// 初回を含めた 1 ダウンロードあたりの試行回数の上限
+    #[arg(long, value_name = "N", default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub tries: u32,
+
+    /// 再試行の待ち時間をランダムに縮める割合（0〜1）。0.5 なら指数バックオフの 50〜100% の間で待ち、1 で 0〜100% になる
+    #[arg(long, value_name = "FRACTION", default_value_t = 0.5, value_parser = parse_fraction)]
+    pub retry_jitter: f64,
+
+    /// UI を表示せず、終了時に集計行だけを出力する（標準出力が TTY でない場合も同様）
+    ///
+    /// 集計行の書式: `SUMMARY ok=<成功数> failed=<失敗数> skipped=<スキップ数> bytes=<合計バイト数> secs=<経過秒>`
+    ///
+    /// Unix では SIGUSR1 を送ると、その時点の進捗を標準エラー出力に書き出す。
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// 終了時に、ファイルごとの結果を終わった順ではなく URL を指定した順に番号付きで出力する
+    #[arg(long, conflicts_with = "quiet_errors_only")]
+    pub report: bool,
+
+    /// UI も集計行も出さず、失敗したダウンロードだけをその都度標準エラー出力に書き出す（cron 向け）
+    #[arg(long, conflicts_with = "quiet")]
+    pub quiet_errors_only: bool,
+
+    /// 各ダウンロードを 1 行の進捗バーで表示する
+    #[arg(long)]
+    pub oneline: bool,
+
+    /// 個別の進捗バーを表示せず、ファイル名と統計情報だけを表示する
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// 完了・スキップしたファイルを 1 件ずつ画面の上に書き出さない。件数はヘッダーの集計にだけ表示する
+    #[arg(long)]
+    pub no_scrollback: bool,
+
+    /// すべて終わったら UI を閉じずに結果の一覧に切り替え、q を押すまで待つ
+    #[arg(long)]
+    pub wait_on_done: bool,
+
+    /// 1 ファイルの受信が 10% 進むごとに「name 10%」の行を残す（UI では画面の上に、--quiet では標準エラー出力に）
+    #[arg(long)]
+    pub milestones: bool,
+
+    /// 各ダウンロードの情報行にダウンロード元の URL を付ける（幅が足りなければ中央を省く）
+    #[arg(long)]
+    pub show_url: bool,
+
+    /// 表示するファイル名の最大幅。長い名前は拡張子が残るよう中央を「…」にして縮める
+    #[arg(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_name_length: Option<usize>,
+
+    /// 各ダウンロードの進捗バーの下に、直近の速度の推移を小さなグラフで表示する
+    #[arg(long)]
+    pub sparklines: bool,
+
+    /// 進捗バーのラベルに出す内容（bytes: 受信済み/合計バイト数を右寄せ、percent: 割合、both: 両方）
+    #[arg(long, value_name = "KIND", value_enum, default_value_t = GaugeLabel::Percent)]
+    pub gauge_label: GaugeLabel,
+
+    /// 速度の単位（bytes: MB/s、bits: 回線の速度と比べやすい Mbps）
+    #[arg(long, value_name = "UNIT", value_enum, default_value_t = SpeedUnit::Bytes)]
+    pub units: SpeedUnit,
+
+    /// 完了したファイルを OS の既定のアプリケーションで開く（画面のない環境では警告だけ出す）
+    #[arg(long)]
+    pub open: bool,
+
+    /// 完了したファイルの SHA-256 を SHA256SUMS 形式で書き出すパス
+    #[arg(long, value_name = "FILE", value_parser = parse_path)]
+    pub checksum_manifest_out: Option<PathBuf>,
+
+    /// 期待する SHA-256 を書いた SHA256SUMS 形式のファイル。終了時に、ファイルごとの期待値と実際の値を並べた照合結果を出力する
+    #[arg(long, value_name = "FILE", value_parser = parse_path)]
+    pub expect_checksums: Option<PathBuf>,
+
+    /// --expect-checksums の照合結果を書き出すファイル
+    #[arg(long, value_name = "FILE", value_parser = parse_path, requires = "expect_checksums")]
+    pub integrity_report: Option<PathBuf>,
+
+    /// ファイルの更新日時とサイズごとに SHA-256 を覚えておくキャッシュ。スキップしたファイルもマニフェストに載せ、
+    /// 変わっていなければ読み直さない
+    #[arg(long, value_name = "FILE", value_parser = parse_path, requires = "checksum_manifest_out", conflicts_with = "tar_stdout")]
+    pub checksum_cache: Option<PathBuf>,
+
+    /// 完了したダウンロードの URL・保存先・サイズ・SHA-256 を JSON Lines で追記するファイル
+    #[arg(long, value_name = "FILE", value_parser = parse_path)]
+    pub audit_log: Option<PathBuf>,
+
+    /// すべて終わったあと、ファイルごとの結果をまとめた JSON をこの URL に POST する（送信に失敗しても終了コードは変えない）
+    #[arg(long, value_name = "URL", value_parser = expand_vars)]
+    pub webhook: Option<String>,
+
+    /// SHA-256 を書き込みとは別のスレッドで計算する（ディスクより回線が速い環境向け）。
+    /// --checksum-manifest-out・--audit-log・--expect-checksums のどれも無ければハッシュを計算しないので何もしない
+    #[arg(long)]
+    pub hash_thread: bool,
+
+    /// ダウンロード後に検証する分離署名の URL またはファイル。`{url}` はダウンロードした URL に置き換える（例: "{url}.asc"）
+    ///
+    /// 検証に失敗したファイルは保存せずに削除し、エラーとして扱う。
+    #[arg(long, value_name = "SIG", value_parser = expand_vars, requires = "gpg_keyring")]
+    pub verify_gpg: Option<String>,
+
+    /// --verify-gpg の署名を検証する公開鍵の鍵束（ASCII 形式またはバイナリ）
+    #[arg(long, value_name = "FILE", value_parser = parse_path, requires = "verify_gpg")]
+    pub gpg_keyring: Option<PathBuf>,
+
+    /// 全ダウンロード合計の帯域上限（バイト/秒、K/M/G の接尾辞可、実行中に s キーの設定パネルで変更できる）
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    pub max_total_rate: Option<u64>,
+
+    /// 新しいダウンロードを始める頻度の上限（リクエスト/秒、0.5 なども可）。API のリクエスト数制限に合わせる
+    #[arg(long, value_name = "REQS", value_parser = parse_rate)]
+    pub rate: Option<f64>,
+
+    /// 1 ファイルあたりに受け取るバイト数の上限。Content-Length に関わらず超えた時点で中断する（K/M/G の接尾辞可）。
+    /// --decompress では展開後に書き込むバイト数にも同じ上限をかける
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    pub max_response_size: Option<u64>,
+
+    /// 保存するファイル名ごとの想定サイズ（"NAME=BYTES" 形式、複数指定可、K/M/G の接尾辞可）。
+    /// Content-Length が違えば、取り違えの可能性として受信を始める前に警告する
+    #[arg(long = "expect-size", value_name = "NAME=BYTES", value_parser = parse_expected_size)]
+    pub expected_sizes: Vec<(String, u64)>,
+
+    /// --expect-size と Content-Length が違うダウンロードを、警告ではなくエラーにして取得しない
+    #[arg(long, requires = "expected_sizes")]
+    pub strict_size: bool,
+
+    /// 1 ファイルのダウンロードにかけてよい秒数。超えたダウンロードだけを中断して失敗にし、ほかはそのまま続ける。
+    /// 一時停止している間は数えない
+    #[arg(long, value_name = "SECS", value_parser = parse_positive_secs)]
+    pub download_timeout: Option<f64>,
+
+    /// 保存するファイル名ごとの --download-timeout（"NAME=SECS" 形式、複数指定可）。指定したファイルだけ --download-timeout より優先する
+    #[arg(long = "download-timeout-for", value_name = "NAME=SECS", value_parser = parse_named_timeout)]
+    pub download_timeouts: Vec<(String, f64)>,
+
+    /// 受信したチャンクを書き込み・ハッシュ計算・帯域制限にかける単位（K/M/G の接尾辞可）。
+    /// 受信するチャンクの大きさは変わらないが、小さくすると帯域制限が滑らかになり、--hash-thread のキューに溜まる量も減る代わりに遅くなる
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    pub buffer_size: Option<u64>,
+
+    /// .gz / .bz2 / .xz のファイルを受信しながら展開し、拡張子を外した名前で保存する（進捗は圧縮後のバイト数）。
+    /// それ以外の拡張子はそのまま保存する
+    #[arg(long, conflicts_with_all = ["concat", "zsync", "repair", "verify_gpg", "resume_all", "resume_only"])]
+    pub decompress: bool,
+
+    /// `.part` への書き込みをまとめるバッファの大きさ（K/M/G の接尾辞可）。小さなチャンクごとに書き込まないようにする
+    #[arg(long, value_name = "BYTES", default_value = "64K", value_parser = parse_bytes)]
+    pub write_buffer: u64,
+
+    /// 移動窓 (--speed-window) の平均速度がこれを下回り続けたら続きから接続し直す（バイト/秒、K/M/G の接尾辞可）
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    pub min_rate: Option<u64>,
+
+    /// レスポンスの Content-Type がこれと異なればエラーにする（例: application/octet-stream、"image/*" も可）
+    #[arg(long, value_name = "TYPE")]
+    pub expect_content_type: Option<String>,
+
+    /// 本文が 0 バイトのレスポンスをエラーとして扱う
+    #[arg(long)]
+    pub fail_empty: bool,
+
+    /// 成功として扱うレスポンスのステータス（カンマ区切り、例: 200,206）。指定すると、ほかのステータスはすべてエラーになる
+    #[arg(long, value_name = "LIST", value_delimiter = ',', value_parser = parse_status)]
+    pub accept_status: Vec<StatusCode>,
+
+    /// ターミナルのウィンドウタイトルに全体の進捗を表示する（--quiet でも標準エラー出力が端末なら表示する）
+    #[arg(long)]
+    pub set_title: bool,
+
+    /// 指定した秒数ごとに `PROGRESS bytes=... pct=... active=...` の 1 行を標準エラー出力に書く（UI の表示中も書く）
+    #[arg(long, value_name = "SECS", value_parser = parse_positive_secs)]
+    pub progress_heartbeat: Option<f64>,
+
+    /// 終了時に保存できたファイルの絶対パスを 1 行ずつ標準出力に書き出す。UI やメッセージは標準エラー出力に出す
+    #[arg(long)]
+    pub print_final_paths: bool,
+
+    /// 保存したファイルを個別に残さず、URL の順に tar アーカイブへまとめて標準出力に書き出す。UI やメッセージは標準エラー出力に出す
+    #[arg(long, conflicts_with_all = ["output_dir", "print_final_paths", "open"])]
+    pub tar_stdout: bool,
+
+    /// POST など冪等でないメソッドでも、接続エラー以外の失敗を再試行する
+    #[arg(long)]
+    pub retry_all_methods: bool,
+
+    /// 保存先に同名のファイルがあればダウンロードしない
+    #[arg(long, conflicts_with = "only_changed")]
+    pub skip_existing: bool,
+
+    /// ダウンロードをスキップしたファイルが 1 つでもあれば、最後まで処理したうえで 0 以外の終了コードで終わる。
+    /// 失敗したダウンロードがあれば、この指定が無くても 0 以外で終わる
+    #[arg(long)]
+    pub fail_on_skip: bool,
+
+    /// 前回保存した ETag / Last-Modified (`<name>.meta`) を使い、更新されたファイルだけを取得する
+    #[arg(long)]
+    pub only_changed: bool,
+
+    /// 手元のファイルより Last-Modified が新しいときだけ取得する (wget -N)。保存したファイルの更新日時はサーバーに合わせる
+    #[arg(short = 'N', long, conflicts_with = "only_changed")]
+    pub timestamping: bool,
+
+    /// 速度と残り時間の算出に使う移動窓の長さ（秒）
+    #[arg(long, value_name = "SECS", default_value_t = 5.0, value_parser = parse_positive_secs)]
+    pub speed_window: f64,
+}
+
+/// 進捗バーのラベルの内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GaugeLabel {
+    Bytes,
+    Percent,
+    Both,
+}
+
+/// 速度を表示する単位
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SpeedUnit {
+    Bits,
+    Bytes,
+}
+
+impl Cli {
+    /// 実行するサブコマンド。省略されていれば download
+    pub fn into_command(self) -> Command {
+        self.command.unwrap_or(Command::Download(self.download))
+    }
+}
+
+impl DownloadArgs {
+    pub fn speed_window(&self) -> Duration {
+        Duration::from_secs_f64(self.speed_window)
+    }
+}
+
+fn parse_positive_secs(s: &str) -> Result<f64, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("数値ではありません: {s}"))?;
+    if secs.is_finite() && secs > 0.0 {
+        Ok(secs)
+    } else {
+        Err(format!("正の秒数を指定してください: {s}"))
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    let fraction: f64 = s.parse().map_err(|_| format!("数値ではありません: {s}"))?;
+    if (0.0..=1.0).contains(&fraction) {
+        Ok(fraction)
+    } else {
+        Err(format!("0 から 1 の間で指定してください: {s}"))
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|_| format!("数値ではありません: {s}"))?;
+    if rate.is_finite() && rate > 0.0 {
+        Ok(rate)
+    } else {
+        Err(format!("0 より大きい値を指定してください: {s}"))
+    }
+}
+
+fn parse_path(s: &str) -> Result<PathBuf, String> {
+    expand_path(s).map(PathBuf::from)
+}
+
+fn parse_header(s: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("\"Name: value\" 形式で指定してください: {s}"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("不正なヘッダー名です: {e}"))?;
+    let value = HeaderValue::from_str(&expand_vars(value.trim())?)
+        .map_err(|e| format!("不正なヘッダー値です: {e}"))?;
+    Ok((name, value))
+}
+
+fn parse_status(s: &str) -> Result<StatusCode, String> {
+    StatusCode::from_bytes(s.trim().as_bytes())
+        .map_err(|_| format!("不正なステータスコードです: {s}"))
+}
+
+fn parse_url(s: &str) -> Result<reqwest::Url, String> {
+    reqwest::Url::parse(&expand_vars(s)?).map_err(|e| format!("不正な URL です ({s}): {e}"))
+}
+
+fn parse_referer(s: &str) -> Result<HeaderValue, String> {
+    HeaderValue::from_str(parse_url(s)?.as_str()).map_err(|e| format!("不正なヘッダー値です: {e}"))
+}
+
+fn parse_origin(s: &str) -> Result<HeaderValue, String> {
+    let origin = parse_url(s)?.origin();
+    if !origin.is_tuple() {
+        return Err(format!("Origin にできない URL です: {s}"));
+    }
+    HeaderValue::from_str(&origin.ascii_serialization())
+        .map_err(|e| format!("不正なヘッダー値です: {e}"))
+}
+
+fn parse_resolve(s: &str) -> Result<(String, SocketAddr), String> {
+    let invalid = || format!("\"host:port:addr\" 形式で指定してください: {s}");
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(invalid());
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    let port: u16 = port
+        .parse()
+        .map_err(|_| format!("不正なポート番号です: {port}"))?;
+    // IPv6 アドレスは "[::1]" のように角括弧で囲んでもよい
+    let addr = addr
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(addr);
+    let ip: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("不正な IP アドレスです: {addr}"))?;
+    Ok((host.to_string(), SocketAddr::new(ip, port)))
+}
+
+fn parse_cookie(s: &str) -> Result<String, String> {
+    let cookie = expand_vars(s)?;
+    match cookie.split_once('=') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(cookie),
+        _ => Err(format!("\"name=value\" 形式で指定してください: {s}")),
+    }
+}
+
+/// "512K" や "10M" のような 1024 単位の接尾辞付きバイト数を解釈する
+fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&s[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    let value: u64 = digits
+        .parse()
+        .map_err(|_| format!("バイト数として解釈できません: {s}"))?;
+    match value.checked_mul(multiplier) {
+        Some(bytes) if bytes > 0 => Ok(bytes),
+        Some(_) => Err("0 より大きい値を指定してください".to_string()),
+        None => Err(format!("値が大きすぎます: {s}")),
+    }
+}
+
+fn parse_expected_size(s: &str) -> Result<(String, u64), String> {
+    let (name, size) = s
+        .rsplit_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("\"NAME=BYTES\" 形式で指定してください: {s}"))?;
+    Ok((name.to_string(), parse_bytes(size)?))
+}
+
+fn parse_named_timeout(s: &str) -> Result<(String, f64), String> {
+    let (name, secs) = s
+        .rsplit_once('=')
+        .filter(|(name, _)| !name.is_empty())
+        .ok_or_else(|| format!("\"NAME=SECS\" 形式で指定してください: {s}"))?;
+    Ok((name.to_string(), parse_positive_secs(secs)?))
+}
+
+fn parse_method(s: &str) -> Result<Method, String> {
+    Method::from_bytes(s.to_ascii_uppercase().as_bytes())
+        .map_err(|_| format!("不正な HTTP メソッドです: {s}"))
+}
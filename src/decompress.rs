@@ -0,0 +1,97 @@
+use std::io::{self, Write};
+
+use bzip2::write::BzDecoder;
+use flate2::write::MultiGzDecoder;
+use xz2::write::XzDecoder;
+
+/// --decompress で展開する単一ストリームの圧縮形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    const EXTENSIONS: [(&'static str, Compression); 3] = [
+        (".gz", Compression::Gzip),
+        (".bz2", Compression::Bzip2),
+        (".xz", Compression::Xz),
+    ];
+
+    /// ファイル名の拡張子から圧縮形式を判定する。知らない拡張子なら None
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::EXTENSIONS
+            .iter()
+            .find(|(ext, _)| name.len() > ext.len() && name.ends_with(ext))
+            .map(|(_, compression)| *compression)
+    }
+
+    /// 展開後のファイル名。知らない拡張子ならそのまま返す
+    pub fn strip_extension(name: &str) -> String {
+        Self::EXTENSIONS
+            .iter()
+            .find_map(|(ext, _)| name.strip_suffix(ext).filter(|stem| !stem.is_empty()))
+            .unwrap_or(name)
+            .to_string()
+    }
+}
+
+/// 書き込まれた内容を展開して `W` に書く。圧縮形式が分からなければそのまま書く
+pub enum Decoder<W: Write> {
+    Plain(W),
+    Gzip(MultiGzDecoder<W>),
+    Bzip2(BzDecoder<W>),
+    Xz(XzDecoder<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(writer: W, compression: Option<Compression>) -> Self {
+        match compression {
+            None => Self::Plain(writer),
+            Some(Compression::Gzip) => Self::Gzip(MultiGzDecoder::new(writer)),
+            Some(Compression::Bzip2) => Self::Bzip2(BzDecoder::new(writer)),
+            Some(Compression::Xz) => Self::Xz(XzDecoder::new(writer)),
+        }
+    }
+
+    /// 展開した内容の書き込み先
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::Plain(writer) => writer,
+            Self::Gzip(decoder) => decoder.get_mut(),
+            Self::Bzip2(decoder) => decoder.get_mut(),
+            Self::Xz(decoder) => decoder.get_mut(),
+        }
+    }
+
+    /// ストリームの終端まで展開し終えたことを確かめて `W` を返す。途中で切れていればエラーになる
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Plain(writer) => Ok(writer),
+            Self::Gzip(decoder) => decoder.finish(),
+            Self::Bzip2(mut decoder) => decoder.finish(),
+            Self::Xz(mut decoder) => decoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(decoder) => decoder.write(buf),
+            Self::Bzip2(decoder) => decoder.write(buf),
+            Self::Xz(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(decoder) => decoder.flush(),
+            Self::Bzip2(decoder) => decoder.flush(),
+            Self::Xz(decoder) => decoder.flush(),
+        }
+    }
+}
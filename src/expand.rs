@@ -0,0 +1,81 @@
+use std::env;
+
+/// `$VAR` / `${VAR}` / `${VAR:-default}` を環境変数の値で置き換える
+///
+/// 未定義の変数はエラーになる（`:-` でデフォルト値を与えた場合を除く）。
+/// `$$` はリテラルの `$` になる。
+pub fn expand_vars(input: &str) -> Result<String, String> {
+    let mut out = String::with_capacity(input.len());
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((_, c)) = chars.next() {
+        if c != '$' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some(&(_, '$')) => {
+                chars.next();
+                out.push('$');
+            }
+            Some(&(start, '{')) => {
+                chars.next();
+                let rest = &input[start + 1..];
+                let end = rest
+                    .find('}')
+                    .ok_or_else(|| format!("閉じ括弧 '}}' がありません: {input}"))?;
+                let body = &rest[..end];
+                let value = match body.split_once(":-") {
+                    Some((name, default)) => lookup(name)?.unwrap_or_else(|| default.to_string()),
+                    None => lookup(body)?.ok_or_else(|| undefined(body))?,
+                };
+                out.push_str(&value);
+                // '}' までの文字を読み飛ばす
+                for (i, _) in chars.by_ref() {
+                    if i == start + 1 + end {
+                        break;
+                    }
+                }
+            }
+            Some(&(start, c)) if is_name_char(c) => {
+                let len = input[start..]
+                    .find(|c: char| !is_name_char(c))
+                    .unwrap_or(input.len() - start);
+                let name = &input[start..start + len];
+                out.push_str(&lookup(name)?.ok_or_else(|| undefined(name))?);
+                for _ in 0..name.chars().count() {
+                    chars.next();
+                }
+            }
+            // 変数名が続かない `$` はそのまま残す
+            _ => out.push('$'),
+        }
+    }
+    Ok(out)
+}
+
+/// パス向けの展開。先頭の `~` をホームディレクトリに置き換えてから変数を展開する
+pub fn expand_path(input: &str) -> Result<String, String> {
+    if input == "~" || input.starts_with("~/") {
+        let home = env::var("HOME")
+            .map_err(|_| "HOME が設定されていないため '~' を展開できません".to_string())?;
+        Ok(format!("{}{}", home, expand_vars(&input[1..])?))
+    } else {
+        expand_vars(input)
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn lookup(name: &str) -> Result<Option<String>, String> {
+    if name.is_empty() || !name.chars().all(is_name_char) {
+        return Err(format!("不正な変数名です: {name}"));
+    }
+    Ok(env::var(name).ok())
+}
+
+fn undefined(name: &str) -> String {
+    format!("環境変数 {name} が定義されていません（${{{name}:-既定値}} で既定値を指定できます）")
+}
@@ -0,0 +1,109 @@
+use std::{fmt, io::Read, path::Path};
+
+use sequoia_openpgp::{
+    cert::{Cert, CertParser},
+    parse::{
+        stream::{DetachedVerifierBuilder, MessageLayer, MessageStructure, VerificationHelper},
+        Parse,
+    },
+    policy::StandardPolicy,
+    KeyHandle,
+};
+
+/// --verify-gpg: ダウンロードしたファイルを分離署名と鍵束で検証する設定
+pub struct Verifier {
+    signature: String,
+    certs: Vec<Cert>,
+}
+
+/// 署名の検証に失敗したことを表すエラー。ダウンロードをやり直しても結果は変わらない
+#[derive(Debug)]
+pub struct VerifyError(pub String);
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "署名の検証に失敗しました: {}", self.0)
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl Verifier {
+    /// `keyring` は ASCII 形式・バイナリ形式どちらの鍵束でもよい
+    pub fn new(signature: String, keyring: &Path) -> Result<Self, String> {
+        let certs = CertParser::from_file(keyring)
+            .and_then(|parser| parser.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| format!("鍵束を読み込めません ({}): {e}", keyring.display()))?;
+        if certs.is_empty() {
+            return Err(format!("鍵束に公開鍵がありません: {}", keyring.display()));
+        }
+        Ok(Self { signature, certs })
+    }
+
+    /// 署名の URL またはファイルのパス。`{url}` をダウンロードした URL に置き換える
+    pub fn signature_location(&self, url: &str) -> String {
+        self.signature.replace("{url}", url)
+    }
+
+    /// 1 つの署名で複数のファイルを検証することになるか
+    pub fn is_fixed(&self) -> bool {
+        !self.signature.contains("{url}")
+    }
+
+    /// `data` から読んだ内容を分離署名 `signature` で検証し、署名した鍵の ID を返す
+    pub fn verify(
+        &self,
+        data: impl Read + Send + Sync,
+        signature: &[u8],
+    ) -> Result<String, VerifyError> {
+        let policy = StandardPolicy::new();
+        let helper = Helper {
+            certs: &self.certs,
+            signer: None,
+        };
+        let mut verifier = DetachedVerifierBuilder::from_bytes(signature)
+            .and_then(|builder| builder.with_policy(&policy, None, helper))
+            .map_err(|e| VerifyError(e.to_string()))?;
+        verifier
+            .verify_reader(data)
+            .map_err(|e| VerifyError(e.to_string()))?;
+        verifier
+            .into_helper()
+            .signer
+            .ok_or_else(|| VerifyError("有効な署名がありません".to_string()))
+    }
+}
+
+struct Helper<'a> {
+    certs: &'a [Cert],
+    signer: Option<String>,
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, _ids: &[KeyHandle]) -> sequoia_openpgp::Result<Vec<Cert>> {
+        Ok(self.certs.to_vec())
+    }
+
+    /// 鍵束の鍵による正しい署名が 1 つでもあれば受け入れる
+    fn check(&mut self, structure: MessageStructure) -> sequoia_openpgp::Result<()> {
+        let mut last_error = None;
+        for layer in structure {
+            let MessageLayer::SignatureGroup { results } = layer else {
+                continue;
+            };
+            for result in results {
+                match result {
+                    Ok(good) => {
+                        self.signer = Some(good.ka.key().keyid().to_hex());
+                        return Ok(());
+                    }
+                    Err(e) => last_error = Some(e.to_string()),
+                }
+            }
+        }
+        Err(sequoia_openpgp::Error::BadSignature(
+            last_error.unwrap_or_else(|| "署名がありません".to_string()),
+        )
+        .into())
+    }
+}
@@ -1,21 +1,71 @@
+mod audit;
+mod checksum_cache;
+mod cli;
+mod decompress;
+mod expand;
+mod gpg;
+mod rate;
+mod scheduler;
+mod sidecar;
+mod state_db;
+mod stderr_ui;
+mod tarstream;
+mod template;
+mod webhook;
+mod zsync;
+
 use std::{
-    collections::BTreeMap,
-    fs::File,
-    io::Write,
-    sync::mpsc,
+    collections::{BTreeMap, VecDeque},
+    ffi::OsStr,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
     thread,
     time::{Duration, Instant},
 };
 
+use clap::Parser;
+use color_eyre::eyre::eyre;
 use color_eyre::Result;
+use cookie_store::{CookieStore, RawCookie};
 use crossterm::event;
+use filetime::FileTime;
 use futures::StreamExt;
+use indicatif::HumanBytes;
 use ratatui::backend::Backend;
 use ratatui::layout::{Constraint, Layout, Rect};
 use ratatui::style::{Color, Modifier, Style};
 use ratatui::text::{Line, Span};
-use ratatui::widgets::{Block, Gauge, LineGauge, Paragraph, Widget};
+use ratatui::widgets::{
+    Block, Cell, Clear, Gauge, LineGauge, Paragraph, Row, Sparkline, Table, Widget,
+};
 use ratatui::{Frame, Terminal, TerminalOptions, Viewport};
+use reqwest::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_MODIFIED_SINCE,
+    IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED, ORIGIN, RANGE, REFERER, RETRY_AFTER,
+};
+use reqwest::{Method, StatusCode};
+use reqwest_cookie_store::CookieStoreMutex;
+use sha2::{Digest, Sha256};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+use crate::audit::AuditLog;
+use crate::checksum_cache::ChecksumCache;
+use crate::cli::{Cli, Command, DownloadArgs, GaugeLabel, SpeedUnit};
+use crate::decompress::{Compression, Decoder};
+use crate::gpg::{Verifier, VerifyError};
+use crate::rate::RateLimiter;
+use crate::scheduler::{ConcurrencyLimit, HostLimits, PauseSwitch};
+use crate::sidecar::Sidecar;
+use crate::state_db::{StateDb, Status as StoredStatus};
+use crate::stderr_ui::StderrBackend;
+use crate::tarstream::{TarStream, TarWriter};
+use crate::template::{OutputTemplate, TemplateValues};
+use crate::webhook::{FileResult, Outcome};
+use crate::zsync::{Manifest, Segment};
 
 type DownloadId = usize;
 
@@ -24,17 +74,110 @@ enum Event {
     Input(event::KeyEvent),
     Tick,
     Resize,
+    DownloadStarted(DownloadId),   // 同時実行数の枠を確保して通信を始めた
+    DownloadSize(DownloadId, u64), // (id, --preflight の HEAD で分かった全体のバイト数)
     DownloadUpdate(DownloadId, u64, u64), // (id, downloaded, total)
-    DownloadDone(DownloadId),
+    DownloadVerifying(DownloadId), // 書き終えて署名を検証している
+    DownloadVerifyUpdate(DownloadId, u64, u64), // (id, 読み返したバイト数, 全体のバイト数)
+    DownloadVerified(DownloadId, String), // (id, 署名した鍵の ID)
+    DownloadRepaired(DownloadId, String), // (id, --repair で直した内容)
+    DownloadDone(DownloadId, Option<String>), // (id, SHA-256)
     DownloadError(DownloadId, String),
+    DownloadRetry(DownloadId, u32, String), // (id, 次の試行回数, 失敗理由)
+    DownloadReconnect(DownloadId, u32, u32, String), // (id, 何回目の再接続か, 再接続の上限, 理由)。試行回数には数えない
+    DownloadWarning(DownloadId, String),             // (id, 続けるが知らせておくこと)
+    DownloadMilestone(DownloadId, u32),              // (id, 越えた進捗の区切り %)
+    DownloadSkipped(DownloadId, SkipReason),         // (id, 理由)
+    DumpProgress,                                    // SIGUSR1 を受け取った
+    Heartbeat,                                       // --progress-heartbeat の間隔が経った
+}
+
+/// ダウンロードしなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SkipReason {
+    Unchanged,       // --only-changed で 304 が返った
+    UpToDate,        // --timestamping で手元のファイルの方が新しいか同じだった
+    NothingToRepair, // --repair で壊れたブロックが無かった
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Unchanged => "変更なし",
+            Self::UpToDate => "手元のファイルが最新です",
+            Self::NothingToRepair => "壊れたブロックはありません",
+        })
+    }
+}
+
+/// 再試行の待ち時間の上限
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Retry-After で待つ時間の上限。極端に長い指定で止まったままにならないようにする
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+/// --min-rate による再接続の上限。超えたら通常の失敗として扱う
+const MAX_SLOW_RESTARTS: u32 = 10;
+/// 接続のリセットによる再接続の上限。超えたら通常の失敗として扱う
+const MAX_RESET_RESUMES: u32 = 5;
+
+/// 個々のダウンロードの状態。完了・失敗したものは in_progress から外れるので持たない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Queued,     // 同時実行数の枠が空くのを待っている
+    Connecting, // 接続してレスポンスの本文を待っている
+    Downloading,
+    Paused,    // 受信中に Space で止められている。表示のときだけ Downloading から読み替える
+    Verifying, // --verify-gpg の署名検証や、再開前のハッシュ計算のためにファイルを読み返している
+}
+
+impl Status {
+    fn label(self) -> &'static str {
+        match self {
+            Self::Queued => "[待機]",
+            Self::Connecting => "[接続]",
+            Self::Downloading => "[受信]",
+            Self::Paused => "[停止]",
+            Self::Verifying => "[検証]",
+        }
+    }
+
+    /// 情報行の先頭に付ける色付きの状態表示
+    fn tag(self) -> Span<'static> {
+        let color = match self {
+            Self::Queued => Color::DarkGray,
+            Self::Connecting => Color::Yellow,
+            Self::Downloading => Color::Cyan,
+            Self::Paused => Color::Yellow,
+            Self::Verifying => Color::Magenta,
+        };
+        Span::styled(
+            self.label(),
+            Style::default().fg(color).add_modifier(Modifier::BOLD),
+        )
+    }
+}
+
+/// 失敗したダウンロードの記録。表示用の文字列にはせず、描画や再試行のときに必要な項目を取り出す。
+/// `y` でコピーし、`e` の一覧から再試行する
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DownloadError {
+    id: DownloadId,
+    name: String,
+    url: String,
+    message: String,
 }
 
 struct DownloadInProgress {
+    #[allow(dead_code)]
     id: DownloadId,
     name: String,
+    url: String, // タスクの URL（--concat では先頭の URL）
     started_at: Instant,
     downloaded: u64,
     total: u64,
+    samples: VecDeque<(Instant, u64)>, // 移動窓内の (時刻, 累計バイト数)
+    status: Status,
+    verify_progress: Option<(u64, u64)>, // 検証のために読み返した (バイト数, 全体)
+    speed_history: VecDeque<u64>,        // --sparklines 用に Tick ごとに記録した速度 (bytes/s)
 }
 
 impl DownloadInProgress {
@@ -45,346 +188,6680 @@ impl DownloadInProgress {
             (self.downloaded as f64 / self.total as f64) * 100.0
         }
     }
-}
-
-struct Downloads {
-    in_progress: BTreeMap<DownloadId, DownloadInProgress>,
-    completed: Vec<String>,
-    errors: Vec<String>,
-}
 
-impl Downloads {
-    fn new() -> Self {
-        Self {
-            in_progress: BTreeMap::new(),
-            completed: Vec::new(),
-            errors: Vec::new(),
+    /// 検証でファイルを読み返している間の (比率, ラベル)。大きなファイルでも止まって見えないようにする
+    fn verify_label(&self) -> Option<(f64, String)> {
+        let (read, total) = self
+            .verify_progress
+            .filter(|_| self.status == Status::Verifying)?;
+        if total == 0 {
+            return None;
         }
+        let ratio = (read as f64 / total as f64).min(1.0);
+        Some((
+            ratio,
+            format!(
+                "検証 {}/{} ({:.1}%)",
+                HumanBytes(read),
+                HumanBytes(total),
+                ratio * 100.0
+            ),
+        ))
     }
-}
-
-async fn download_with_progress(
-    id: DownloadId,
-    url: &str,
-    filename: &str,
-    tx: mpsc::Sender<Event>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let response = client.get(url).send().await?;
-    let total_size = response.content_length().unwrap_or(0);
-    
-    let mut file = File::create(filename)?;
-    let mut stream = response.bytes_stream();
-    let mut downloaded = 0u64;
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        file.write_all(&chunk)?;
-        downloaded += chunk.len() as u64;
-        
-        tx.send(Event::DownloadUpdate(id, downloaded, total_size))?;
-        
-        // 進捗更新の間隔を調整（より滑らかな表示のため）
-        tokio::time::sleep(Duration::from_millis(50)).await;
+    /// 書き込んだバイト数が Content-Length (圧縮後のサイズ) を超えているか
+    fn is_decompressed(&self) -> bool {
+        self.total > 0 && self.downloaded > self.total
     }
 
-    tx.send(Event::DownloadDone(id))?;
-    Ok(())
-}
-
-fn input_handling(tx: mpsc::Sender<Event>) {
-    let tick_rate = Duration::from_millis(200);
-    thread::spawn(move || {
-        let mut last_tick = Instant::now();
-        loop {
-            let timeout = tick_rate.saturating_sub(last_tick.elapsed());
-            if event::poll(timeout).unwrap() {
-                match event::read().unwrap() {
-                    event::Event::Key(key) => tx.send(Event::Input(key)).unwrap(),
-                    event::Event::Resize(_, _) => tx.send(Event::Resize).unwrap(),
-                    _ => {}
-                }
-            }
-            if last_tick.elapsed() >= tick_rate {
-                tx.send(Event::Tick).unwrap();
-                last_tick = Instant::now();
+    /// 現在の累計バイト数を記録し、窓から外れた古いサンプルを捨てる
+    fn record_sample(&mut self, window: Duration) {
+        let now = Instant::now();
+        self.samples.push_back((now, self.downloaded));
+        // 窓の始点を跨ぐサンプルを 1 つだけ残し、常に窓幅ぶんの区間を測れるようにする
+        while let Some(&(t, _)) = self.samples.get(1) {
+            if now.duration_since(t) >= window {
+                self.samples.pop_front();
+            } else {
+                break;
             }
         }
-    });
-}
+    }
 
-fn run<B: Backend>(
-    terminal: &mut Terminal<B>,
-    mut downloads: Downloads,
-    rx: mpsc::Receiver<Event>,
-) -> Result<()> {
-    let mut redraw = true;
-    loop {
-        if redraw {
-            terminal.draw(|frame| render(frame, &downloads))?;
+    /// 移動窓内の平均速度 (bytes/s)
+    fn speed(&self) -> f64 {
+        let (Some(&(t0, b0)), Some(&(_, b1))) = (self.samples.front(), self.samples.back()) else {
+            return 0.0;
+        };
+        // 更新が途絶えた場合に速度が下がっていくよう、終点は現在時刻とする
+        let elapsed = t0.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            b1.saturating_sub(b0) as f64 / elapsed
+        } else {
+            0.0
         }
-        redraw = true;
+    }
 
-        match rx.recv()? {
-            Event::Input(event) => {
-                if event.code == event::KeyCode::Char('q') {
-                    break;
-                }
-            }
-            Event::Resize => {
-                terminal.autoresize()?;
-            }
-            Event::Tick => {}
-            Event::DownloadUpdate(id, downloaded, total) => {
-                if let Some(download) = downloads.in_progress.get_mut(&id) {
-                    download.downloaded = downloaded;
-                    download.total = total;
-                }
-                redraw = false;
-            }
-            Event::DownloadDone(id) => {
-                if let Some(download) = downloads.in_progress.remove(&id) {
-                    let duration = download.started_at.elapsed();
-                    let size_mb = download.total as f64 / 1_048_576.0;
-                    
-                    terminal.insert_before(1, |buf| {
-                        Paragraph::new(Line::from(vec![
-                            Span::from("✓ ダウンロード完了: "),
-                            Span::styled(
-                                download.name.clone(),
-                                Style::default().add_modifier(Modifier::BOLD).fg(Color::Green),
-                            ),
-                            Span::from(format!(
-                                " ({:.2}MB, {}ms)",
-                                size_mb,
-                                duration.as_millis()
-                            )),
-                        ]))
-                        .render(buf.area, buf);
-                    })?;
-                    
-                    downloads.completed.push(download.name);
-                    
-                    if downloads.in_progress.is_empty() {
-                        terminal.insert_before(1, |buf| {
-                            Paragraph::new(Line::from(vec![
-                                Span::styled("🎉 ", Style::default().fg(Color::Yellow)),
-                                Span::styled("すべてのダウンロードが完了しました！", Style::default().fg(Color::Green).add_modifier(Modifier::BOLD)),
-                            ])).render(buf.area, buf);
-                        })?;
-                        break;
-                    }
-                }
-            }
-            Event::DownloadError(id, error) => {
-                if let Some(download) = downloads.in_progress.remove(&id) {
-                    terminal.insert_before(1, |buf| {
-                        Paragraph::new(Line::from(vec![
-                            Span::from("❌ エラー: "),
-                            Span::styled(
-                                download.name.clone(),
-                                Style::default().add_modifier(Modifier::BOLD).fg(Color::Red),
-                            ),
-                            Span::from(format!(" - {}", error)),
-                        ]))
-                        .render(buf.area, buf);
-                    })?;
-                    downloads.errors.push(format!("{}: {}", download.name, error));
-                }
-            }
+    fn eta(&self) -> Option<Duration> {
+        let speed = self.speed();
+        if self.total == 0 || self.is_decompressed() || speed <= 0.0 {
+            return None;
         }
+        let remaining = self.total.saturating_sub(self.downloaded) as f64;
+        Some(Duration::from_secs_f64(remaining / speed))
     }
-    Ok(())
 }
 
-fn render(frame: &mut Frame, downloads: &Downloads) {
-    let area = frame.area();
+fn format_eta(eta: Duration) -> String {
+    let secs = eta.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
 
-    let block = Block::new().title(Line::from("📥 ファイルダウンロード進捗").centered());
-    frame.render_widget(block, area);
+/// --sparklines で覚えておく速度の数。Tick (200ms) ごとに 1 つなので直近 24 秒分
+const SPARKLINE_LEN: usize = 120;
 
-    let vertical = Layout::vertical([
-        Constraint::Length(2), // 全体の進捗
-        Constraint::Length(3), // ヘッダー
-        Constraint::Min(4),    // ダウンロード詳細
-    ]).margin(1);
-    
-    let areas = vertical.split(area);
-    let progress_area = areas[0];
-    let header_area = areas[1];
-    let details_area = areas[2];
+const SPINNER_FRAMES: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
 
-    // 全体の進捗
-    let total_downloads = downloads.completed.len() + downloads.in_progress.len();
-    let completed_downloads = downloads.completed.len();
-    
-    let progress = if total_downloads > 0 {
-        completed_downloads as f64 / total_downloads as f64
-    } else {
-        0.0
-    };
-    
-    let overall_progress = LineGauge::default()
-        .filled_style(Style::default().fg(Color::Green))
-        .label(format!("全体進捗 {}/{}", completed_downloads, total_downloads))
-        .ratio(progress);
-    frame.render_widget(overall_progress, progress_area);
+/// ダウンロード詳細の表示形式
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ViewMode {
+    Full,
+    Oneline,
+    Table,
+}
 
-    // ヘッダー情報
-    let header_text = if downloads.in_progress.is_empty() {
-        if downloads.completed.is_empty() {
-            "ダウンロード待機中..."
-        } else {
-            "ダウンロード完了"
+impl ViewMode {
+    /// `v` キーで切り替える次の表示形式
+    fn next(self) -> Self {
+        match self {
+            Self::Full => Self::Oneline,
+            Self::Oneline => Self::Table,
+            Self::Table => Self::Full,
         }
-    } else {
-        "ダウンロード中..."
-    };
-    
-    let header = Paragraph::new(Line::from(vec![
-        Span::styled(
-            header_text,
-            Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD),
-        ),
-    ]));
-    frame.render_widget(header, header_area);
+    }
+}
 
-    // 個別ダウンロードの詳細
-    let mut y_offset = 0;
-    for (_, download) in downloads.in_progress.iter() {
-        if y_offset >= details_area.height.saturating_sub(2) {
-            break;
-        }
+/// `s` で開く設定パネルの項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Setting {
+    Concurrency,
+    TotalRate,
+    PerHost,
+}
 
-        // ファイル名と統計情報
-        let info_area = Rect {
-            x: details_area.x,
-            y: details_area.y + y_offset,
-            width: details_area.width,
-            height: 1,
-        };
-        
-        let downloaded_mb = download.downloaded as f64 / 1_048_576.0;
-        let total_mb = download.total as f64 / 1_048_576.0;
-        let speed = if download.started_at.elapsed().as_secs() > 0 {
-            downloaded_mb / download.started_at.elapsed().as_secs() as f64
-        } else {
-            0.0
-        };
-        
-        let info_text = if download.total > 0 {
-            format!(
-                "📦 {} ({:.2}/{:.2}MB, {:.2}MB/s)",
-                download.name, downloaded_mb, total_mb, speed
-            )
+impl Setting {
+    const ALL: [Setting; 3] = [Setting::Concurrency, Setting::TotalRate, Setting::PerHost];
+
+    /// ↑↓ キーで選ぶ前後の項目。端で止める
+    fn moved(self, down: bool) -> Self {
+        let index = Self::ALL
+            .iter()
+            .position(|setting| *setting == self)
+            .unwrap_or(0);
+        let index = if down {
+            (index + 1).min(Self::ALL.len() - 1)
         } else {
-            format!("📦 {} ({:.2}MB, サイズ不明)", download.name, downloaded_mb)
+            index.saturating_sub(1)
         };
-        
-        let info = Paragraph::new(Line::from(vec![
-            Span::styled(info_text, Style::default().fg(Color::White)),
-        ]));
-        frame.render_widget(info, info_area);
+        Self::ALL[index]
+    }
+}
 
-        // 進捗バー
-        let gauge_area = Rect {
-            x: details_area.x,
-            y: details_area.y + y_offset + 1,
-            width: details_area.width,
-            height: 1,
-        };
+/// 設定パネルで ←→ キーを押したときに選ぶ合計帯域の段階（バイト/秒）。この上は無制限
+const RATE_STEPS: [u64; 12] = [
+    64 << 10,
+    128 << 10,
+    256 << 10,
+    512 << 10,
+    1 << 20,
+    2 << 20,
+    4 << 20,
+    8 << 20,
+    16 << 20,
+    32 << 20,
+    64 << 20,
+    128 << 20,
+];
 
-        let progress_ratio = download.progress() / 100.0;
-        let gauge = Gauge::default()
-            .gauge_style(Style::default().fg(Color::Yellow))
-            .percent((progress_ratio * 100.0) as u16)
-            .label(format!("{:.1}%", progress_ratio * 100.0));
-        
-        frame.render_widget(gauge, gauge_area);
-        
-        y_offset += 3;
-    }
+struct Downloads {
+    in_progress: BTreeMap<DownloadId, DownloadInProgress>,
+    completed: Vec<String>,
+    errors: Vec<DownloadError>,
+    error_panel: Option<usize>, // `e` で開いた失敗一覧で選んでいる位置
+    copied: usize,              // `y` を押した回数。新しい失敗から順にさかのぼる
+    skipped: Vec<String>,
+    up_to_date: usize, // skipped のうち --timestamping で手元が最新だったもの
+    speed_window: Duration,
+    tries: u32,
+    view_mode: ViewMode,
+    no_progress: bool,              // 進捗バーを描かず情報行だけを表示する
+    no_scrollback: bool,            // 完了・スキップの行を画面の上に書き出さない
+    wait_on_done: bool,             // 終わったら結果の一覧に切り替えて q を待つ
+    results_scroll: u16,            // 結果の一覧のスクロール位置
+    show_url: bool,                 // 情報行にダウンロード元の URL を付ける
+    max_name_length: Option<usize>, // 表示するファイル名の最大幅
+    sparklines: bool,               // 進捗バーの下に速度の推移を表示する
+    gauge_label: GaugeLabel,        // 進捗バーのラベルの内容
+    units: SpeedUnit,               // 速度の単位
+    set_title: bool,                // ターミナルのタイトルに全体進捗を表示する
+    concurrency: Arc<ConcurrencyLimit>,
+    total_rate: Arc<RateLimiter>, // 全ダウンロード合計の帯域上限。0 は無制限
+    host_limits: Arc<HostLimits>,
+    pause: Arc<PauseSwitch>,
+    completed_bytes: u64,
+    checksums: BTreeMap<DownloadId, String>, // 完了したダウンロードの SHA-256
+    signers: BTreeMap<DownloadId, String>,   // --verify-gpg で署名を確かめた鍵の ID
+    repairs: BTreeMap<DownloadId, String>,   // --repair で直した内容
+    outcomes: BTreeMap<DownloadId, Outcome>, // 終わったダウンロードの結果
+    peak_speed: f64, // これまでに観測した 1 ダウンロードの最高速度 (bytes/s)
+    tick: u64,       // スピナーのコマ送り用
+    url_input: Option<String>, // `a` で開いた URL 入力欄の内容
+    settings: Option<Setting>, // `s` で開いた設定パネルで選んでいる項目
+    integrity: Vec<String>, // --expect-checksums の照合結果。すべて終わったときに結果の一覧にも出す
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    color_eyre::install()?;
-    let mut terminal = ratatui::init_with_options(TerminalOptions {
-        viewport: Viewport::Inline(15),
-    });
+impl Downloads {
+    fn new(cli: &DownloadArgs) -> Self {
+        Self {
+            in_progress: BTreeMap::new(),
+            completed: Vec::new(),
+            errors: Vec::new(),
+            error_panel: None,
+            copied: 0,
+            skipped: Vec::new(),
+            up_to_date: 0,
+            speed_window: cli.speed_window(),
+            tries: cli.tries,
+            view_mode: if cli.oneline {
+                ViewMode::Oneline
+            } else {
+                ViewMode::Full
+            },
+            no_progress: cli.no_progress,
+            no_scrollback: cli.no_scrollback,
+            wait_on_done: cli.wait_on_done,
+            results_scroll: 0,
+            show_url: cli.show_url,
+            max_name_length: cli.max_name_length,
+            sparklines: cli.sparklines,
+            gauge_label: cli.gauge_label,
+            units: cli.units,
+            set_title: cli.set_title,
+            concurrency: Arc::new(ConcurrencyLimit::new(cli.jobs)),
+            total_rate: Arc::new(RateLimiter::new(cli.max_total_rate.unwrap_or(0) as f64)),
+            host_limits: Arc::new(HostLimits::new(cli.max_per_host)),
+            pause: Arc::new(PauseSwitch::new()),
+            completed_bytes: 0,
+            checksums: BTreeMap::new(),
+            signers: BTreeMap::new(),
+            repairs: BTreeMap::new(),
+            outcomes: BTreeMap::new(),
+            peak_speed: 0.0,
+            tick: 0,
+            url_input: None,
+            settings: None,
+            integrity: Vec::new(),
+        }
+    }
 
-    let (tx, rx) = mpsc::channel();
-    input_handling(tx.clone());
-    
-    let mut downloads = Downloads::new();
-    
-    // 複数のファイルをダウンロードするサンプル
-    let download_tasks = vec![
-        (0, "http://archive.ubuntu.com/ubuntu/pool/universe/b/bmon/bmon_4.0-6_amd64.deb", "bmon.deb"),
-        (1, "https://httpbin.org/bytes/1024", "sample1.bin"),
-        (2, "https://httpbin.org/bytes/2048", "sample2.bin"),
-    ];
+    /// 設定パネルで選んでいる項目を 1 段階上げる (`up`) か下げる。変更はすぐに新しいダウンロードへ効く
+    fn adjust_setting(&mut self, up: bool) {
+        let Some(setting) = self.settings else {
+            return;
+        };
+        match setting {
+            Setting::Concurrency => {
+                let limit = self.concurrency.limit();
+                self.concurrency.set_limit(if up {
+                    limit + 1
+                } else {
+                    limit.saturating_sub(1)
+                });
+            }
+            Setting::TotalRate => {
+                let rate = self.total_rate.rate() as u64;
+                let rate = match (up, rate) {
+                    (true, 0) => 0,
+                    (true, rate) => RATE_STEPS
+                        .into_iter()
+                        .find(|step| *step > rate)
+                        .unwrap_or(0),
+                    (false, 0) => RATE_STEPS[RATE_STEPS.len() - 1],
+                    (false, rate) => RATE_STEPS
+                        .into_iter()
+                        .rev()
+                        .find(|step| *step < rate)
+                        .unwrap_or(RATE_STEPS[0]),
+                };
+                self.total_rate.set_rate(rate as f64);
+            }
+            // 同時実行数以上にしても意味がないので、そこまで上げたら無制限にする
+            Setting::PerHost => {
+                let limit = self.host_limits.limit();
+                let max = self.concurrency.limit().saturating_sub(1).max(1);
+                let limit = match (up, limit) {
+                    (true, 0) => 0,
+                    (true, limit) if limit >= max => 0,
+                    (true, limit) => limit + 1,
+                    (false, 0) => max,
+                    (false, limit) => limit.saturating_sub(1).max(1),
+                };
+                self.host_limits.set_limit(limit);
+            }
+        }
+    }
 
-    // 全ダウンロードタスクを開始
-    for (id, url, filename) in &download_tasks {
-        let id = *id;
-        downloads.in_progress.insert(
+    /// 起動したタスクを待機中として加える。表示名は --max-name-length に収める
+    fn queue(&mut self, id: DownloadId, name: &str, url: &str, downloaded: u64) {
+        let name = match self.max_name_length {
+            Some(max_width) => truncate_middle(name, max_width),
+            None => name.to_string(),
+        };
+        self.in_progress.insert(
             id,
             DownloadInProgress {
                 id,
-                name: filename.to_string(),
+                name,
+                url: url.to_string(),
                 started_at: Instant::now(),
-                downloaded: 0,
+                downloaded,
                 total: 0,
+                samples: VecDeque::new(),
+                speed_history: VecDeque::new(),
+                status: Status::Queued,
+                verify_progress: None,
             },
         );
+    }
 
-        let tx_clone = tx.clone();
-        let url_owned = url.to_string();
-        let filename_owned = filename.to_string();
-        
-        tokio::spawn(async move {
-            if let Err(e) = download_with_progress(id, &url_owned, &filename_owned, tx_clone.clone()).await {
-                let _ = tx_clone.send(Event::DownloadError(id, e.to_string()));
-            }
-        });
+    fn start(&mut self, id: DownloadId) {
+        // 待ち時間を所要時間に含めないよう、開始時刻を取り直す
+        if let Some(download) = self.in_progress.get_mut(&id) {
+            download.started_at = Instant::now();
+            download.status = Status::Connecting;
+        }
     }
 
-    // 実行前にターミナルを閉じる
-    ratatui::restore();
+    /// --preflight で分かった全体のサイズを、本文を受け取る前から表示に使う
+    fn set_total(&mut self, id: DownloadId, total: u64) {
+        if let Some(download) = self.in_progress.get_mut(&id) {
+            download.total = total;
+        }
+    }
 
-    // ダウンロードが完了したら、.deb ファイルをインストールする
-    println!("すべてのダウンロードが完了しました。");
-    let deb_files: Vec<&str> = download_tasks
-        .iter()
-        .filter_map(|(_, _, filename)| {
-            if filename.ends_with(".deb") {
-                Some(*filename)
-            } else {
-                None
-            }
-        })
-        .collect();
+    fn set_status(&mut self, id: DownloadId, status: Status) {
+        if let Some(download) = self.in_progress.get_mut(&id) {
+            download.status = status;
+        }
+    }
 
-    if !deb_files.is_empty() {
-        println!(".deb ファイルのインストールを試みます...");
-        let mut args = vec!["-i"];
-        args.extend(deb_files);
+    fn verify_update(&mut self, id: DownloadId, read: u64, total: u64) {
+        if let Some(download) = self.in_progress.get_mut(&id) {
+            download.status = Status::Verifying;
+            download.verify_progress = Some((read, total));
+        }
+    }
+
+    /// 受信中のダウンロードの現在の速度を履歴に足す。古いものから捨てて SPARKLINE_LEN 個に保つ
+    fn record_speeds(&mut self) {
+        for download in self.in_progress.values_mut() {
+            if download.status != Status::Downloading {
+                continue;
+            }
+            download.speed_history.push_back(download.speed() as u64);
+            if download.speed_history.len() > SPARKLINE_LEN {
+                download.speed_history.pop_front();
+            }
+        }
+    }
 
-        let status = std::process::Command::new("sudo")
-            .arg("dpkg")
-            .args(&args)
-            .status()?;
+    fn update(&mut self, id: DownloadId, downloaded: u64, total: u64) {
+        if let Some(download) = self.in_progress.get_mut(&id) {
+            download.status = Status::Downloading;
+            download.verify_progress = None;
+            download.downloaded = downloaded;
+            download.total = total;
+            download.record_sample(self.speed_window);
+            self.peak_speed = self.peak_speed.max(download.speed());
+        }
+    }
 
-        if status.success() {
-            println!("インストールが正常に完了しました。");
+    /// 完了したダウンロードを記録し、表示用に取り出す
+    fn finish(&mut self, id: DownloadId, sha256: Option<String>) -> Option<DownloadInProgress> {
+        let download = self.in_progress.remove(&id)?;
+        if let Some(sha256) = sha256 {
+            self.checksums.insert(id, sha256);
+        }
+        self.completed_bytes += download.downloaded;
+        self.completed.push(download.name.clone());
+        self.outcomes.insert(id, Outcome::Done);
+        Some(download)
+    }
+
+    fn skip(&mut self, id: DownloadId, reason: SkipReason) -> Option<DownloadInProgress> {
+        let download = self.in_progress.remove(&id)?;
+        self.skipped.push(download.name.clone());
+        if reason == SkipReason::UpToDate {
+            self.up_to_date += 1;
+        }
+        self.outcomes
+            .insert(id, Outcome::Skipped(reason.to_string()));
+        Some(download)
+    }
+
+    fn fail(&mut self, id: DownloadId, error: &str) -> Option<DownloadInProgress> {
+        let download = self.in_progress.remove(&id)?;
+        self.errors.push(DownloadError {
+            id,
+            name: download.name.clone(),
+            url: download.url.clone(),
+            message: error.to_string(),
+        });
+        self.outcomes.insert(id, Outcome::Failed(error.to_string()));
+        Some(download)
+    }
+
+    /// --wait-on-done で、すべて終わって結果の一覧を出している
+    fn finished(&self) -> bool {
+        self.wait_on_done && self.in_progress.is_empty()
+    }
+
+    /// `y` でコピーする失敗したダウンロードの (表示名, URL)。押すたびに新しいものから順にさかのぼり、一巡したら戻る
+    fn next_failed_url(&mut self) -> Option<(String, String)> {
+        let len = self.errors.len();
+        let index = len.checked_sub(1 + self.copied % len.max(1))?;
+        self.copied += 1;
+        self.errors
+            .get(index)
+            .map(|failed| (failed.name.clone(), failed.url.clone()))
+    }
+
+    /// 失敗一覧の選択を動かす。一覧が空になっていれば閉じる
+    fn move_error_selection(&mut self, down: bool) {
+        let last = self.errors.len().checked_sub(1);
+        self.error_panel = match (self.error_panel, last) {
+            (Some(selected), Some(last)) if down => Some((selected + 1).min(last)),
+            (Some(selected), Some(last)) => Some(selected.saturating_sub(1).min(last)),
+            _ => None,
+        };
+    }
+
+    /// スクリプトから解析しやすい 1 行の集計。項目の並びと書式は変えないこと
+    fn summary_line(&self, elapsed: Duration) -> String {
+        format!(
+            "SUMMARY ok={} failed={} skipped={} bytes={} secs={:.1}",
+            self.completed.len(),
+            self.errors.len(),
+            self.skipped.len(),
+            self.completed_bytes,
+            elapsed.as_secs_f64()
+        )
+    }
+
+    /// 表示に使う状態。一時停止中は受信中のダウンロードを [停止] として見せる
+    fn status_of(&self, download: &DownloadInProgress) -> Status {
+        match download.status {
+            Status::Downloading if self.pause.is_paused() => Status::Paused,
+            status => status,
+        }
+    }
+
+    /// SIGUSR1 で標準エラー出力に書き出す現在の進捗。1 行目が件数、以降が進行中のダウンロード
+    fn progress_snapshot(&self) -> Vec<String> {
+        let mut lines = vec![format!(
+            "PROGRESS active={} ok={} failed={} skipped={}",
+            self.in_progress.len(),
+            self.completed.len(),
+            self.errors.len(),
+            self.skipped.len()
+        )];
+        for download in self.in_progress.values() {
+            let bytes = if download.total > 0 {
+                format!(
+                    "{}/{} ({:.1}%)",
+                    HumanBytes(download.downloaded),
+                    HumanBytes(download.total),
+                    download.progress().min(100.0)
+                )
+            } else {
+                HumanBytes(download.downloaded).to_string()
+            };
+            lines.push(format!(
+                "  {} {} {} {}",
+                self.status_of(download).label(),
+                download.name,
+                bytes,
+                format_speed(download.speed(), self.units)
+            ));
+        }
+        lines
+    }
+}
+
+#[derive(Clone)]
+struct DownloadTask {
+    id: DownloadId,
+    url: String,
+    path: PathBuf,
+    resume_from: u64, // 既存の .part から再開するバイト数
+}
+
+/// 全タスク共通のダウンロード設定
+#[derive(Clone)]
+struct DownloadOptions {
+    client: reqwest::Client,
+    method: Method,
+    body: Option<Vec<u8>>, // --data / --data-file で指定したリクエスト本文
+    headers: HeaderMap,
+    use_server_timestamps: bool,
+    timestamping: bool, // 手元のファイルよりサーバーの方が新しいときだけ取得する
+    tries: u32,         // 初回を含む試行回数の上限
+    retry_jitter: f64,  // 再試行の待ち時間をランダムに縮める割合
+    checksum: bool,     // 書き込みと並行して SHA-256 を計算する
+    hash_thread: bool,  // SHA-256 を書き込みとは別のスレッドで計算する
+    rate_limiter: Option<Arc<RateLimiter>>, // 全ダウンロード合計の帯域制限
+    pause: Option<Arc<PauseSwitch>>, // Space で切り替える全体の一時停止
+    fail_empty: bool,
+    accept_status: Vec<StatusCode>, // 空でなければ、これらのステータスだけを成功とする
+    retry_all_methods: bool,
+    only_changed: bool, // サイドカーの検証子で条件付きリクエストを送る
+    expect_content_type: Option<String>, // 200 で返るエラーページを弾くための期待する Content-Type
+    min_rate: Option<u64>, // 移動窓の平均速度がこれを下回ったら接続し直す（バイト/秒）
+    speed_window: Duration,
+    gpg: Option<Arc<Verifier>>,         // --verify-gpg の署名検証
+    max_response_size: Option<u64>,     // 1 ファイルに書き込むバイト数の上限
+    download_timeout: Option<Duration>, // 1 回のダウンロードにかけてよい時間
+    download_timeouts: BTreeMap<String, Duration>, // --download-timeout-for: 保存するファイル名ごとの制限時間
+    expected_sizes: BTreeMap<String, u64>, // --expect-size: 保存するファイル名ごとの想定サイズ
+    strict_size: bool,                     // 想定サイズと違えば警告ではなくエラーにする
+    write_buffer: usize,                   // .part への書き込みをまとめるバッファの大きさ
+    buffer_size: Option<usize>,            // 受信したチャンクをこの大きさ以下に分けて処理する
+    decompress: bool,                      // .gz / .bz2 / .xz を展開しながら保存する
+    milestones: bool,                      // 10% ごとに DownloadMilestone を送る
+}
+
+impl DownloadOptions {
+    fn hasher(&self) -> Option<ChunkHasher> {
+        self.checksum.then(|| ChunkHasher::new(self.hash_thread))
+    }
+
+    /// `path` に保存するダウンロードの制限時間。ファイル名ごとの指定があればそちらを使う
+    fn timeout_for(&self, path: &Path) -> Option<Duration> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        self.download_timeouts
+            .get(name.as_ref())
+            .copied()
+            .or(self.download_timeout)
+    }
+}
+
+/// ダウンロード中の一時ファイルのパス (`<name>.part`)
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// 保存先と `.part` に書き込めるかを通信の前に確かめ、よくある失敗を原因ごとに説明する
+fn check_writable(path: &Path) -> Result<(), String> {
+    let fail = |reason: &str| format!("cannot write to {}: {}", path.display(), reason);
+    if path.is_dir() {
+        return Err(fail("is a directory"));
+    }
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    if !parent.exists() {
+        return Err(fail("parent directory does not exist"));
+    }
+    if !parent.is_dir() {
+        return Err(fail("parent is not a directory"));
+    }
+    // 再開用の .part が既にあれば中身を変えないよう追記モードで開く
+    let part = part_path(path);
+    let existed = part.exists();
+    match OpenOptions::new().create(true).append(true).open(&part) {
+        Ok(_) => {
+            if !existed {
+                let _ = fs::remove_file(&part);
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Err(fail("permission denied")),
+        Err(e) => Err(fail(&e.to_string())),
+    }
+}
+
+/// 書き終えた `from` を `to` に移す。別のファイルシステムで rename できなければ、`to` の隣に
+/// コピーしてから rename し、書きかけのファイルが `to` の名前で見えないようにする
+fn move_into_place(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            let mut staging = to.as_os_str().to_owned();
+            staging.push(".moving");
+            let staging = PathBuf::from(staging);
+            if let Err(e) = fs::copy(from, &staging).and_then(|_| fs::rename(&staging, to)) {
+                let _ = fs::remove_file(&staging);
+                return Err(e);
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// 429/503 に Retry-After が付いていたことを表すエラー。再試行はこの待ち時間に従う
+#[derive(Debug)]
+struct RetryAfterError {
+    status: StatusCode,
+    delay: Duration,
+}
+
+impl fmt::Display for RetryAfterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HTTP {} (Retry-After: {}秒)",
+            self.status,
+            self.delay.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for RetryAfterError {}
+
+/// --accept-status に含まれないステータスが返ったことを表すエラー
+#[derive(Debug)]
+struct UnacceptedStatusError {
+    status: StatusCode,
+}
+
+impl fmt::Display for UnacceptedStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HTTP {} (--accept-status に含まれていません)",
+            self.status
+        )
+    }
+}
+
+impl std::error::Error for UnacceptedStatusError {}
+
+/// Content-Type のメディアタイプ部分を期待値と比べる。`type/*` はサブタイプを問わない
+fn check_content_type(headers: &HeaderMap, expected: &str) -> Result<(), String> {
+    let actual = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase()
+        })
+        .unwrap_or_default();
+    let expected = expected.trim().to_ascii_lowercase();
+    let matches = match expected.strip_suffix("/*") {
+        Some(main_type) => actual.split_once('/').is_some_and(|(t, _)| t == main_type),
+        None => actual == expected,
+    };
+    if matches {
+        Ok(())
+    } else {
+        let actual = if actual.is_empty() {
+            "(なし)"
         } else {
-            eprintln!("インストールに失敗しました。終了コード: {:?}", status.code());
+            actual.as_str()
+        };
+        Err(format!(
+            "unexpected content type: {} (期待値: {})",
+            actual, expected
+        ))
+    }
+}
+
+/// --min-rate の監視で速度不足と判定されたことを表すエラー
+#[derive(Debug)]
+struct SlowDownloadError {
+    rate: u64,
+}
+
+impl fmt::Display for SlowDownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "速度が下限を下回ったため再接続します ({:.2}MB/s)",
+            self.rate as f64 / 1_048_576.0
+        )
+    }
+}
+
+impl std::error::Error for SlowDownloadError {}
+
+/// --strict-size で、Content-Length が --expect-size と違ったことを表すエラー。取り直しても同じなので再試行しない
+#[derive(Debug)]
+struct SizeMismatchError {
+    actual: u64,
+    expected: u64,
+}
+
+impl fmt::Display for SizeMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "サイズが想定と違います (Content-Length: {} バイト, 期待値: {} バイト)",
+            self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for SizeMismatchError {}
+
+/// 受信中に接続がリセットされたことを表すエラー。受け取った分は .part に残っているので続きから取り直せる
+#[derive(Debug)]
+struct ConnectionResetError {
+    kind: io::ErrorKind,
+    received: u64,
+}
+
+impl fmt::Display for ConnectionResetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "受信中に接続が切れました ({}, {} バイト受信済み)",
+            self.kind, self.received
+        )
+    }
+}
+
+impl std::error::Error for ConnectionResetError {}
+
+/// 本文の読み取りエラーの原因をたどり、続きから取り直せる接続の切断なら、その種類を返す
+fn reset_kind(error: &(dyn std::error::Error + 'static)) -> Option<io::ErrorKind> {
+    let mut source = Some(error);
+    while let Some(error) = source {
+        if let Some(kind) = error.downcast_ref::<io::Error>().map(io::Error::kind) {
+            return matches!(
+                kind,
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            )
+            .then_some(kind);
         }
+        source = error.source();
     }
+    None
+}
 
-    let app_result = run(&mut terminal, downloads, rx);
-    ratatui::restore();
-    
-    app_result
-}
\ No newline at end of file
+/// --max-response-size を超えたことを表すエラー。取り直しても同じなので再試行しない
+#[derive(Debug)]
+struct ResponseTooLargeError {
+    limit: u64,
+}
+
+impl fmt::Display for ResponseTooLargeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "レスポンスが上限 ({} バイト) を超えたため中断しました",
+            self.limit
+        )
+    }
+}
+
+impl std::error::Error for ResponseTooLargeError {}
+
+/// --download-timeout の時間内に終わらなかったことを表すエラー。取り直しても間に合わないので再試行しない
+#[derive(Debug)]
+struct DownloadTimeoutError;
+
+impl fmt::Display for DownloadTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "download timed out")
+    }
+}
+
+impl std::error::Error for DownloadTimeoutError {}
+
+/// Retry-After を秒数または HTTP 日付として解釈する。MAX_RETRY_AFTER より長い指定は切り詰める
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    let delay = match value.parse::<u64>() {
+        Ok(secs) => Duration::from_secs(secs),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            // 過去の日付ならすぐに再試行してよい
+            date.duration_since(std::time::SystemTime::now())
+                .unwrap_or_default()
+        }
+    };
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// エラーステータスを Err にする。429/503 で Retry-After があれば待ち時間を添える。
+/// --accept-status を指定した場合は、そこに挙げたステータスだけを成功とする
+fn check_status(
+    response: reqwest::Response,
+    accept: &[StatusCode],
+) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+    let status = response.status();
+    if accept.contains(&status) {
+        return Ok(response);
+    }
+    if matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+    ) {
+        if let Some(delay) = retry_after(response.headers()) {
+            return Err(Box::new(RetryAfterError { status, delay }));
+        }
+    }
+    if !accept.is_empty() {
+        return Err(Box::new(UnacceptedStatusError { status }));
+    }
+    Ok(response.error_for_status()?)
+}
+
+/// ダウンロード中の `.part` ファイル。書き込んだバイト数と、必要ならその SHA-256 を追跡する。
+/// 書き込みは --write-buffer の大きさにまとめるので、読み返す前に finish でフラッシュする
+/// --decompress では本文を展開しながら書き、written には受信した圧縮後のバイト数を数える
+///
+/// 書き込み先はファイルに固定している。この crate はバイナリだけでライブラリとして使われないので、
+/// ダウンロードごとに `AsyncWrite` を差し込む口は、ライブラリに切り出すときに HashingWriter の
+/// `BufWriter<File>` を差し替えられるようにして作る
+struct PartFile {
+    file: Decoder<HashingWriter>,
+    written: u64,
+}
+
+/// `.part` への書き込み。ハッシュは展開後の、実際にファイルへ書く内容で取る
+struct HashingWriter {
+    file: BufWriter<File>,
+    hasher: Option<ChunkHasher>,
+    written: u64,       // 展開後のバイト数
+    limit: Option<u64>, // 展開後のバイト数の上限 (--max-response-size)
+}
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // 小さな圧縮データが巨大に展開されることがあるので、受信したバイト数とは別に書く量でも止める
+        if let Some(limit) = self.limit {
+            if self.written + buf.len() as u64 > limit {
+                return Err(io::Error::other(ResponseTooLargeError { limit }));
+            }
+        }
+        let n = self.file.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// 書き込んだ内容の SHA-256 を計算する
+enum ChunkHasher {
+    Inline(Sha256),
+    /// --hash-thread: チャンクを別スレッドに送り、書き込みとハッシュ計算を重ねる
+    Threaded {
+        chunks: mpsc::SyncSender<Vec<u8>>,
+        digest: thread::JoinHandle<String>,
+    },
+}
+
+/// ハッシュ用スレッドに溜められるチャンク数。ハッシュが追いつかなければ書き込み側が待つ
+const HASH_QUEUE_CHUNKS: usize = 64;
+
+impl ChunkHasher {
+    fn new(threaded: bool) -> Self {
+        if !threaded {
+            return Self::Inline(Sha256::new());
+        }
+        let (chunks, rx) = mpsc::sync_channel::<Vec<u8>>(HASH_QUEUE_CHUNKS);
+        let digest = thread::spawn(move || {
+            let mut hasher = Sha256::new();
+            for chunk in rx {
+                hasher.update(&chunk);
+            }
+            hex(&hasher.finalize())
+        });
+        Self::Threaded { chunks, digest }
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        match self {
+            Self::Inline(hasher) => hasher.update(chunk),
+            // 受け手のスレッドは finish まで終わらないので送信は失敗しない
+            Self::Threaded { chunks, .. } => {
+                let _ = chunks.send(chunk.to_vec());
+            }
+        }
+    }
+
+    /// 16 進文字列のダイジェストを返す。別スレッドの場合は残りのチャンクを処理し終えるまで待つ
+    fn finish(self) -> Option<String> {
+        match self {
+            Self::Inline(hasher) => Some(hex(&hasher.finalize())),
+            Self::Threaded { chunks, digest } => {
+                drop(chunks);
+                digest.join().ok()
+            }
+        }
+    }
+}
+
+/// 進捗を通知する間隔。読み返しは速いので、チャンクごとに送るとイベントが溢れる
+const VERIFY_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 書き終えたファイルを読み返しながら DownloadVerifyUpdate を送る
+struct ProgressReader<R> {
+    inner: R,
+    id: DownloadId,
+    read: u64,
+    total: u64,
+    reported_at: Option<Instant>,
+    tx: mpsc::Sender<Event>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    fn new(inner: R, id: DownloadId, total: u64, tx: &mpsc::Sender<Event>) -> Self {
+        Self {
+            inner,
+            id,
+            read: 0,
+            total,
+            reported_at: None,
+            tx: tx.clone(),
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        // 最初と最後 (n == 0) は必ず送る
+        let due = self
+            .reported_at
+            .is_none_or(|at| at.elapsed() >= VERIFY_REPORT_INTERVAL);
+        if n == 0 || due {
+            self.reported_at = Some(Instant::now());
+            let _ = self
+                .tx
+                .send(Event::DownloadVerifyUpdate(self.id, self.read, self.total));
+        }
+        Ok(n)
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl PartFile {
+    fn create(
+        path: &Path,
+        hasher: Option<ChunkHasher>,
+        capacity: usize,
+        compression: Option<Compression>,
+    ) -> io::Result<Self> {
+        let file = HashingWriter {
+            file: BufWriter::with_capacity(capacity, File::create(path)?),
+            hasher,
+            written: 0,
+            limit: None,
+        };
+        Ok(Self {
+            file: Decoder::new(file, compression),
+            written: 0,
+        })
+    }
+
+    /// 既存の `.part` に追記する。ハッシュを取る場合は書き込み済みの部分を先に読み込み、その進捗を通知する
+    fn append(
+        path: &Path,
+        mut hasher: Option<ChunkHasher>,
+        capacity: usize,
+        id: DownloadId,
+        tx: &mpsc::Sender<Event>,
+    ) -> io::Result<Self> {
+        if let Some(hasher) = &mut hasher {
+            let file = File::open(path)?;
+            let len = file.metadata()?.len();
+            let mut existing = ProgressReader::new(file, id, len, tx);
+            let mut buf = vec![0u8; 64 * 1024];
+            loop {
+                let n = existing.read(&mut buf)?;
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+        }
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            written: file.metadata()?.len(),
+            file: Decoder::new(
+                HashingWriter {
+                    file: BufWriter::with_capacity(capacity, file),
+                    hasher,
+                    written: 0,
+                    limit: None,
+                },
+                None,
+            ),
+        })
+    }
+
+    /// 展開後に書くバイト数の上限を設ける。超えると write が ResponseTooLargeError を返す
+    fn limit_output(&mut self, limit: Option<u64>) {
+        self.file.get_mut().limit = limit;
+    }
+
+    fn write(&mut self, chunk: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = self.file.write_all(chunk) {
+            // 上限を超えた場合は、再試行しないよう ResponseTooLargeError として返す
+            if e.get_ref()
+                .is_some_and(|inner| inner.is::<ResponseTooLargeError>())
+            {
+                return Err(e.into_inner().unwrap());
+            }
+            return Err(Box::new(e));
+        }
+        self.written += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// 展開し終えたことを確かめ、バッファを書き出してファイルを閉じる。ハッシュを取っていれば 16 進文字列で返す
+    fn finish(self) -> io::Result<Option<String>> {
+        let mut file = self.file.finish()?;
+        file.flush()?;
+        Ok(file.hasher.and_then(ChunkHasher::finish))
+    }
+}
+
+/// --milestones で知らせる進捗の刻み (%)
+const MILESTONE_STEP: u32 = 10;
+
+/// 受信量が MILESTONE_STEP の区切りを越えたかを追う。再開した場合は既に越えた区切りを知らせない
+struct Milestones {
+    total: u64,
+    next: u32,
+}
+
+impl Milestones {
+    /// サイズが分からなければ割合も出せないので None
+    fn new(written: u64, total: u64) -> Option<Self> {
+        (total > 0).then(|| Self {
+            total,
+            next: (Self::percent(written, total) / MILESTONE_STEP + 1) * MILESTONE_STEP,
+        })
+    }
+
+    fn percent(written: u64, total: u64) -> u32 {
+        (written.min(total) as u128 * 100 / total as u128) as u32
+    }
+
+    /// `written` までに越えた区切りを返す
+    fn reached(&mut self, written: u64) -> Vec<u32> {
+        let percent = Self::percent(written, self.total);
+        let mut reached = Vec::new();
+        while self.next <= percent {
+            reached.push(self.next);
+            self.next += MILESTONE_STEP;
+        }
+        reached
+    }
+}
+
+/// レスポンス本文をファイルに書き込みながら進捗を通知する
+async fn write_body(
+    id: DownloadId,
+    response: reqwest::Response,
+    part: &mut PartFile,
+    total_size: u64,
+    options: &DownloadOptions,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if let Some(limit) = options.max_response_size {
+        if total_size > limit {
+            return Err(Box::new(ResponseTooLargeError { limit }));
+        }
+    }
+    let mut stream = response.bytes_stream();
+    let started_at = Instant::now();
+    let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+    let mut milestones = if options.milestones {
+        Milestones::new(part.written, total_size)
+    } else {
+        None
+    };
+
+    // 止めていた間にサーバーが接続を閉じることがあるため、一度でも止めたかを覚えておく
+    let mut paused = false;
+    loop {
+        // --min-rate 指定時は、窓の間ずっとデータが来なければ速度 0 とみなす
+        let next = match options.min_rate {
+            Some(_) => match tokio::time::timeout(options.speed_window, stream.next()).await {
+                Ok(next) => next,
+                Err(_) => return Err(Box::new(SlowDownloadError { rate: 0 })),
+            },
+            None => stream.next().await,
+        };
+        let Some(chunk) = next else {
+            break;
+        };
+        // 一時停止中は次のチャンクを読まずに待つ。止まっていた間は速度の判定に含めない
+        if let Some(pause) = &options.pause {
+            if pause.wait().await {
+                paused = true;
+                samples.clear();
+            }
+        }
+        // 一時停止の後に読み取りが失敗したら、接続が切られたものとして .part の続きから取り直す
+        let chunk = chunk.map_err(|e| -> Box<dyn std::error::Error> {
+            match reset_kind(&e) {
+                Some(kind) => Box::new(ConnectionResetError {
+                    kind,
+                    received: part.written,
+                }),
+                None if paused => Box::new(ConnectionResetError {
+                    kind: io::ErrorKind::ConnectionAborted,
+                    received: part.written,
+                }),
+                None => Box::new(e),
+            }
+        })?;
+        // 申告された Content-Length を信用せず、実際に受け取ったバイト数で判定する
+        if let Some(limit) = options.max_response_size {
+            if part.written + chunk.len() as u64 > limit {
+                return Err(Box::new(ResponseTooLargeError { limit }));
+            }
+        }
+        // サーバー次第で大きなチャンクが届くことがあるため、--buffer-size ごとに区切る。
+        // ハッシュ用スレッドのキューにはこの大きさのコピーが溜まる。受信したチャンクはすでにメモリにあるので、
+        // 進捗の通知はチャンクごとに 1 回にする
+        let piece_size = options.buffer_size.unwrap_or(chunk.len()).max(1);
+        for piece in chunk.chunks(piece_size) {
+            if let Some(limiter) = &options.rate_limiter {
+                limiter.acquire(piece.len()).await;
+            }
+            part.write(piece)?;
+        }
+        tx.send(Event::DownloadUpdate(id, part.written, total_size))?;
+        if let Some(milestones) = &mut milestones {
+            for percent in milestones.reached(part.written) {
+                tx.send(Event::DownloadMilestone(id, percent))?;
+            }
+        }
+
+        if let Some(min_rate) = options.min_rate {
+            let now = Instant::now();
+            samples.push_back((now, part.written));
+            while samples.len() > 2 && now - samples[1].0 >= options.speed_window {
+                samples.pop_front();
+            }
+            // 接続してから窓の長さが経つまでは判定しない
+            let (oldest_at, oldest) = samples[0];
+            let elapsed = now - oldest_at;
+            if now - started_at >= options.speed_window && elapsed >= options.speed_window {
+                let rate = ((part.written - oldest) as f64 / elapsed.as_secs_f64()) as u64;
+                if rate < min_rate {
+                    return Err(Box::new(SlowDownloadError { rate }));
+                }
+            }
+        }
+        
+        // 進捗更新の間隔を調整（より滑らかな表示のため）
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    Ok(())
+}
+
+async fn download_with_progress(
+    task: DownloadTask,
+    options: DownloadOptions,
+    tx: mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = task.id;
+    let client = &options.client;
+    let mut request = client
+        .request(options.method.clone(), &task.url)
+        .headers(options.headers.clone());
+    if let Some(body) = &options.body {
+        request = request.body(body.clone());
+    }
+    // 前回の検証子があれば、変わっていないときに 304 を返してもらう
+    let previous = if options.only_changed && task.path.exists() {
+        Sidecar::load(&task.path)
+    } else {
+        None
+    };
+    if let Some(previous) = &previous {
+        if let Some(etag) = &previous.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &previous.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    // --timestamping: 手元のファイルより新しいときだけ本文を返してもらう
+    let local_modified = if options.timestamping {
+        fs::metadata(&task.path)
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    } else {
+        None
+    };
+    if let Some(local_modified) = local_modified {
+        request = request.header(IF_MODIFIED_SINCE, httpdate::fmt_http_date(local_modified));
+    }
+    let part = part_path(&task.path);
+    // 展開しながら書いた .part の長さは受信したバイト数と合わないので、続きからは取らない
+    let compression = if options.decompress {
+        Compression::from_name(&filename_from_url(&task.url))
+    } else {
+        None
+    };
+    let resume_from = if compression.is_some() {
+        0
+    } else {
+        task.resume_from
+    };
+    if resume_from > 0 {
+        request = request.header(RANGE, format!("bytes={}-", resume_from));
+        // .part を取り始めたときの検証子があれば、ファイルが変わっていない場合だけ続きを返してもらう
+        let partial = Sidecar::load(&part);
+        if let Some(if_range) = partial.as_ref().and_then(Sidecar::if_range) {
+            request = request.header(IF_RANGE, if_range);
+        }
+    }
+    let response = request.send().await?;
+    // .part が既に最後までそろっていると、続きを頼んだ Range に 416 が返る
+    if resume_from > 0
+        && response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+        && range_complete(response.headers(), resume_from)
+    {
+        drop(response);
+        return finish_complete_part(task, &options, &tx).await;
+    }
+    let response = check_status(response, &options.accept_status)?;
+    if previous.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        tx.send(Event::DownloadSkipped(id, SkipReason::Unchanged))?;
+        return Ok(());
+    }
+    // 解釈できない日付は無視する
+    let last_modified = response
+        .headers()
+        .get(LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| httpdate::parse_http_date(value).ok());
+    // If-Modified-Since を無視するサーバーもあるので、返ってきた Last-Modified でも比べる
+    if let Some(local_modified) = local_modified {
+        if response.status() == StatusCode::NOT_MODIFIED
+            || last_modified.is_some_and(|remote| remote <= local_modified)
+        {
+            tx.send(Event::DownloadSkipped(id, SkipReason::UpToDate))?;
+            return Ok(());
+        }
+    }
+    if let Some(expected) = &options.expect_content_type {
+        check_content_type(response.headers(), expected)?;
+    }
+    let validators = Sidecar::from_headers(response.headers());
+
+    // 206 が返らなければ Range が無視されたかファイルが変わっているので最初から取り直す
+    let resumed = resume_from > 0 && response.status() == StatusCode::PARTIAL_CONTENT;
+    let offset = if resumed { resume_from } else { 0 };
+    let total_size = response
+        .content_length()
+        .map(|len| len + offset)
+        .unwrap_or(0);
+    // 受信を始める前に、別のファイルやバージョンを取りに行っていないか確かめる
+    let name = task.path.file_name().unwrap_or_default().to_string_lossy();
+    if let (Some(&expected), Some(_)) = (
+        options.expected_sizes.get(name.as_ref()),
+        response.content_length(),
+    ) {
+        if total_size != expected {
+            let mismatch = SizeMismatchError {
+                actual: total_size,
+                expected,
+            };
+            if options.strict_size {
+                return Err(Box::new(mismatch));
+            }
+            tx.send(Event::DownloadWarning(id, mismatch.to_string()))?;
+        }
+    }
+    let mut file = if resumed {
+        PartFile::append(&part, options.hasher(), options.write_buffer, id, &tx)?
+    } else {
+        // 次に再開するときの If-Range のため、取り始めたファイルの検証子を残す
+        if validators.is_empty() {
+            sidecar::remove(&part)?;
+        } else {
+            validators.save(&part)?;
+        }
+        let mut file =
+            PartFile::create(&part, options.hasher(), options.write_buffer, compression)?;
+        file.limit_output(options.max_response_size);
+        file
+    };
+    // 接続が途中で閉じられたら、本文の読み取りエラーも含めて受信できたバイト数で報告する。
+    // .part は残すので再試行で続きから取得する
+    if let Err(e) = write_body(id, response, &mut file, total_size, &options, &tx).await {
+        let cut_short = e
+            .downcast_ref::<reqwest::Error>()
+            .is_some_and(|e| e.is_body() || e.is_decode());
+        if !(cut_short && file.written < total_size) {
+            return Err(e);
+        }
+    }
+    if file.written < total_size {
+        return Err(format!("truncated: got {} of {} bytes", file.written, total_size).into());
+    }
+    if options.fail_empty && file.written == 0 {
+        drop(file);
+        fs::remove_file(&part)?;
+        sidecar::remove(&part)?;
+        return Err("empty response: 本文が 0 バイトでした".into());
+    }
+    let sha256 = file.finish()?;
+    if let Err(e) = verify_signature(id, &part, &task.url, &options, &tx).await {
+        sidecar::remove(&part)?;
+        return Err(e);
+    }
+    move_into_place(&part, &task.path)?;
+    sidecar::remove(&part)?;
+    if options.only_changed && !validators.is_empty() {
+        validators.save(&task.path)?;
+    }
+    if options.use_server_timestamps {
+        if let Some(modified) = last_modified {
+            filetime::set_file_mtime(&task.path, FileTime::from_system_time(modified))?;
+        }
+    }
+    tx.send(Event::DownloadDone(id, sha256))?;
+    Ok(())
+}
+
+/// --verify-gpg: 書き終えた `.part` を分離署名で検証する。
+/// 署名を取得できない場合も含め、失敗したら `.part` を消して保存先に残さない
+async fn verify_signature(
+    id: DownloadId,
+    part: &Path,
+    url: &str,
+    options: &DownloadOptions,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(verifier) = &options.gpg else {
+        return Ok(());
+    };
+    tx.send(Event::DownloadVerifying(id))?;
+    let location = verifier.signature_location(url);
+    let result = match fetch_location(&location, options).await {
+        Ok(signature) => File::open(part)
+            .and_then(|file| Ok((file.metadata()?.len(), file)))
+            .map_err(|e| VerifyError(format!("ファイルを読めません: {e}")))
+            .and_then(|(len, file)| {
+                verifier.verify(ProgressReader::new(file, id, len, tx), &signature)
+            }),
+        Err(e) => Err(VerifyError(format!(
+            "署名を取得できません ({location}): {e}"
+        ))),
+    };
+    match result {
+        Ok(signer) => {
+            tx.send(Event::DownloadVerified(id, signer))?;
+            Ok(())
+        }
+        Err(e) => {
+            fs::remove_file(part)?;
+            Err(Box::new(e))
+        }
+    }
+}
+
+/// `http://` / `https://` で始まれば取得し、それ以外はファイルとして読む
+async fn fetch_location(
+    location: &str,
+    options: &DownloadOptions,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    if location.starts_with("http://") || location.starts_with("https://") {
+        let response = options
+            .client
+            .get(location)
+            .headers(options.headers.clone())
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.bytes().await?.to_vec())
+    } else {
+        Ok(fs::read(location)?)
+    }
+}
+
+/// HEAD のレスポンスが申告する本文の長さ。HEAD の本文は空なので content_length() ではなくヘッダーを直接読む
+fn head_length(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+}
+
+/// --preflight: 本文を受け取る前に HEAD で全体のサイズを調べる。
+/// HEAD を受け付けないサーバーやサイズを返さないサーバーでは None になり、GET で分かるのを待つ
+async fn preflight_size(url: &str, options: &DownloadOptions) -> Option<u64> {
+    let response = options
+        .client
+        .head(url)
+        .headers(options.headers.clone())
+        .send()
+        .await
+        .ok()?
+        .error_for_status()
+        .ok()?;
+    head_length(&response).filter(|&len| len > 0)
+}
+
+/// 416 の Content-Range (`bytes */<全体>`) が、手元の `.part` で全部そろっていることを示すか。
+/// Content-Range が無ければ、サーバーの言うとおり続きは無いものとする
+fn range_complete(headers: &HeaderMap, resume_from: u64) -> bool {
+    let Some(value) = headers.get(CONTENT_RANGE) else {
+        return true;
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.strip_prefix("bytes */"))
+        .and_then(|total| total.trim().parse::<u64>().ok())
+        .is_some_and(|total| total == resume_from)
+}
+
+/// 取得済みの `.part` を完了したファイルとして扱う。ハッシュと署名は読み返して確かめる
+async fn finish_complete_part(
+    task: DownloadTask,
+    options: &DownloadOptions,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = task.id;
+    let part = part_path(&task.path);
+    let file = PartFile::append(&part, options.hasher(), options.write_buffer, id, tx)?;
+    tx.send(Event::DownloadUpdate(id, file.written, file.written))?;
+    let sha256 = file.finish()?;
+    if let Err(e) = verify_signature(id, &part, &task.url, options, tx).await {
+        sidecar::remove(&part)?;
+        return Err(e);
+    }
+    move_into_place(&part, &task.path)?;
+    sidecar::remove(&part)?;
+    tx.send(Event::DownloadDone(id, sha256))?;
+    Ok(())
+}
+
+/// 複数の URL を順番に取得し、1 つのファイルに連結して保存する
+async fn download_concat(
+    task: DownloadTask,
+    urls: Vec<String>,
+    options: DownloadOptions,
+    tx: mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = task.id;
+
+    // 全体の進捗を出すため、先に HEAD でサイズを集める。HEAD を受け付けないなど
+    // 1 つでも分からなければ全体も不明として、そのまま取得に進む
+    let mut sizes = Vec::with_capacity(urls.len());
+    for url in &urls {
+        sizes.push(preflight_size(url, &options).await);
+    }
+    let total_size = if sizes.iter().all(Option::is_some) {
+        sizes.iter().flatten().sum()
+    } else {
+        0
+    };
+
+    // 途中で失敗したら組み立てかけの .part は続きから使えないので消す
+    let part = part_path(&task.path);
+    let mut file = PartFile::create(&part, options.hasher(), options.write_buffer, None)?;
+    if let Err(e) = write_concat(id, &urls, &sizes, &mut file, total_size, &options, &tx).await {
+        drop(file);
+        fs::remove_file(&part)?;
+        return Err(e);
+    }
+    let sha256 = file.finish()?;
+    verify_signature(id, &part, &task.url, &options, &tx).await?;
+    move_into_place(&part, &task.path)?;
+    tx.send(Event::DownloadDone(id, sha256))?;
+    Ok(())
+}
+
+/// --concat: 各 URL の本文を順に `file` の末尾へ書き足す。HEAD で分かった長さに足りなければ失敗にする
+async fn write_concat(
+    id: DownloadId,
+    urls: &[String],
+    sizes: &[Option<u64>],
+    file: &mut PartFile,
+    total_size: u64,
+    options: &DownloadOptions,
+    tx: &mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (url, size) in urls.iter().zip(sizes) {
+        let response = options
+            .client
+            .get(url)
+            .headers(options.headers.clone())
+            .send()
+            .await?;
+        let response = check_status(response, &options.accept_status)?;
+        let before = file.written;
+        write_body(id, response, file, total_size, options, tx).await?;
+        // 1 つでも短ければ、次の本文がずれた位置に続いてしまう
+        let got = file.written - before;
+        if let Some(expected) = size.filter(|&expected| got < expected) {
+            return Err(format!("truncated: {url}: got {got} of {expected} bytes").into());
+        }
+    }
+    if total_size > 0 && file.written != total_size {
+        return Err(format!("truncated: got {} of {} bytes", file.written, total_size).into());
+    }
+    if options.fail_empty && file.written == 0 {
+        return Err("empty response: 本文が 0 バイトでした".into());
+    }
+    Ok(())
+}
+
+/// --zsync: マニフェストと手元の旧版を比べ、変わったブロックだけを Range で取得して組み立てる。
+/// --repair (`repair`) では同じ位置のブロックだけを照合し、壊れたブロックを取得し直す
+async fn download_zsync(
+    task: DownloadTask,
+    manifest_location: String,
+    repair: bool,
+    options: DownloadOptions,
+    tx: mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let id = task.id;
+    let client = &options.client;
+
+    let text = String::from_utf8(fetch_location(&manifest_location, &options).await?)?;
+    let manifest = Manifest::parse(&text)?;
+    let (segments, repaired) = if repair {
+        let len = match fs::metadata(&task.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) => {
+                return Err(format!(
+                    "修復するファイルを開けません ({}): {e}",
+                    task.path.display()
+                )
+                .into())
+            }
+        };
+        let (segments, broken) = manifest.repair_segments(&task.path)?;
+        let repaired = match (broken.is_empty(), len > manifest.length) {
+            (true, false) => {
+                tx.send(Event::DownloadSkipped(id, SkipReason::NothingToRepair))?;
+                return Ok(());
+            }
+            (true, true) => "length を超える末尾を削除".to_string(),
+            (false, _) => format!("ブロック {}", zsync::format_blocks(&broken)),
+        };
+        (segments, Some(repaired))
+    } else {
+        (manifest.segments(Some(&task.path))?, None)
+    };
+
+    let part = part_path(&task.path);
+    let mut file = PartFile::create(&part, options.hasher(), options.write_buffer, None)?;
+    for segment in segments {
+        match segment {
+            Segment::Local { offset, len } => {
+                // まとめた区間はファイル全体になることもあるので、一度に読まず少しずつ写す
+                let mut local = File::open(&task.path)?;
+                local.seek(SeekFrom::Start(offset))?;
+                let mut local = local.take(len);
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = local.read(&mut buf)?;
+                    if n == 0 {
+                        break;
+                    }
+                    file.write(&buf[..n])?;
+                    tx.send(Event::DownloadUpdate(id, file.written, manifest.length))?;
+                }
+                if local.limit() != 0 {
+                    return Err(
+                        format!("読み込み中に {} が短くなりました", task.path.display()).into(),
+                    );
+                }
+            }
+            Segment::Remote { start, len } => {
+                let response = client
+                    .get(&task.url)
+                    .headers(options.headers.clone())
+                    .header(RANGE, format!("bytes={}-{}", start, start + len - 1))
+                    .send()
+                    .await?;
+                let response = check_status(response, &[])?;
+                if response.status() != StatusCode::PARTIAL_CONTENT {
+                    return Err(
+                        "サーバーが Range リクエストに対応していないため差分取得できません".into(),
+                    );
+                }
+                write_body(id, response, &mut file, manifest.length, &options, &tx).await?;
+                if file.written != start + len {
+                    return Err(format!(
+                        "要求した範囲 (bytes={}-{}) と異なる長さの応答でした",
+                        start,
+                        start + len - 1
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+    let sha256 = file.finish()?;
+
+    // 取得したブロックが壊れていないか、組み立てた結果をマニフェストと照合する
+    let mut assembled = File::open(&part)?;
+    for (index, expected) in manifest.blocks.iter().enumerate() {
+        let mut buf = vec![0u8; manifest.block_len(index) as usize];
+        assembled.read_exact(&mut buf)?;
+        if zsync::hash_hex(&buf) != *expected {
+            return Err(
+                format!("ブロック {} のハッシュがマニフェストと一致しません", index).into(),
+            );
+        }
+    }
+    if assembled.read(&mut [0u8; 1])? != 0 {
+        return Err(format!(
+            "組み立てたファイルが length ({} バイト) より長くなりました",
+            manifest.length
+        )
+        .into());
+    }
+    drop(assembled);
+
+    verify_signature(id, &part, &task.url, &options, &tx).await?;
+    move_into_place(&part, &task.path)?;
+    if let Some(repaired) = repaired {
+        tx.send(Event::DownloadRepaired(id, repaired))?;
+    }
+    tx.send(Event::DownloadDone(id, sha256))?;
+    Ok(())
+}
+
+/// 再試行してよい失敗か。POST のような冪等でないリクエストは、サーバーに届いていない
+/// 接続エラーに限って再試行する（--retry-all-methods で常に再試行）
+fn is_retryable(error: &(dyn std::error::Error + 'static), options: &DownloadOptions) -> bool {
+    // 署名が合わないファイルや大きすぎるレスポンスは取り直しても同じ
+    if error.is::<VerifyError>()
+        || error.is::<ResponseTooLargeError>()
+        || error.is::<DownloadTimeoutError>()
+        || error.is::<SizeMismatchError>()
+    {
+        return false;
+    }
+    if options.retry_all_methods || options.method.is_idempotent() {
+        return true;
+    }
+    error
+        .downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect())
+}
+
+/// download_with_progress を --download-timeout の時間内に限って実行する
+async fn download_with_timeout(
+    task: DownloadTask,
+    options: DownloadOptions,
+    tx: mpsc::Sender<Event>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let limit = options.timeout_for(&task.path);
+    let pause = options.pause.clone();
+    limit_download_time(limit, pause, download_with_progress(task, options, tx)).await
+}
+
+/// 一時停止を確かめる間隔。止めている間はダウンロードの制限時間を減らさない
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `download` を `limit` の時間内に限って実行する。一時停止していた間は時間に数えない
+async fn limit_download_time(
+    limit: Option<Duration>,
+    pause: Option<Arc<PauseSwitch>>,
+    download: impl std::future::Future<Output = Result<(), Box<dyn std::error::Error>>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(mut remaining) = limit else {
+        return download.await;
+    };
+    let Some(pause) = pause else {
+        return tokio::time::timeout(remaining, download)
+            .await
+            .unwrap_or_else(|_| Err(Box::new(DownloadTimeoutError)));
+    };
+    tokio::pin!(download);
+    loop {
+        let checked_at = Instant::now();
+        tokio::select! {
+            result = &mut download => return result,
+            _ = tokio::time::sleep(remaining.min(PAUSE_CHECK_INTERVAL)) => {}
+        }
+        if !pause.is_paused() {
+            remaining = remaining.saturating_sub(checked_at.elapsed());
+            if remaining.is_zero() {
+                return Err(Box::new(DownloadTimeoutError));
+            }
+        }
+    }
+}
+
+/// バックオフの待ち時間を `fraction` の割合までランダムに縮める。
+/// 同じホストで一斉に失敗したダウンロードが、同じ間隔で一斉に取り直さないようにする
+fn jittered(delay: Duration, fraction: f64, random: f64) -> Duration {
+    delay.mul_f64(1.0 - fraction * random)
+}
+
+/// `tries` 回まで download_with_progress を試行する。再試行時は .part の続きから取得する
+async fn download_with_tries(
+    mut task: DownloadTask,
+    options: DownloadOptions,
+    tx: mpsc::Sender<Event>,
+) -> Result<(), String> {
+    let mut attempt = 1;
+    let mut delay = Duration::from_secs(1);
+    let mut slow_restarts = 0;
+    let mut reset_resumes = 0;
+    loop {
+        let (error, requested_delay, retryable, slow, reset) =
+            match download_with_timeout(task.clone(), options.clone(), tx.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(e) => (
+                    e.to_string(),
+                    e.downcast_ref::<RetryAfterError>().map(|e| e.delay),
+                    is_retryable(e.as_ref(), &options),
+                    e.is::<SlowDownloadError>(),
+                    e.is::<ConnectionResetError>(),
+                ),
+            };
+        if !retryable {
+            return Err(error);
+        }
+        // 接続のリセットも同じく試行回数に数えず、受け取った分の続きからすぐに取り直す
+        if reset && reset_resumes < MAX_RESET_RESUMES {
+            reset_resumes += 1;
+            let _ = tx.send(Event::DownloadReconnect(
+                task.id,
+                reset_resumes,
+                MAX_RESET_RESUMES,
+                error,
+            ));
+            task.resume_from = fs::metadata(part_path(&task.path))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            continue;
+        }
+        // 速度不足による再接続は試行回数に数えず、待たずに続きから取り直す
+        if slow && slow_restarts < MAX_SLOW_RESTARTS {
+            slow_restarts += 1;
+            let _ = tx.send(Event::DownloadReconnect(
+                task.id,
+                slow_restarts,
+                MAX_SLOW_RESTARTS,
+                error,
+            ));
+            task.resume_from = fs::metadata(part_path(&task.path))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+            continue;
+        }
+        if attempt >= options.tries {
+            return Err(if options.tries > 1 {
+                format!("max tries exceeded ({}回): {}", options.tries, error)
+            } else {
+                error
+            });
+        }
+        attempt += 1;
+        let _ = tx.send(Event::DownloadRetry(task.id, attempt, error));
+
+        // サーバーが待ち時間を指定した場合はバックオフよりそちらを優先する
+        match requested_delay {
+            Some(requested) => tokio::time::sleep(requested).await,
+            None => {
+                tokio::time::sleep(jittered(delay, options.retry_jitter, fastrand::f64())).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+        task.resume_from = fs::metadata(part_path(&task.path))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+    }
+}
+
+/// タスクを起動し、起動済みのタスクを記録する。UI から URL を追加するときにも使う
+struct TaskQueue {
+    options: DownloadOptions,
+    layout: OutputLayout,
+    skip_existing: bool,
+    concat_urls: Option<Vec<String>>, // --concat で連結する URL
+    zsync_manifest: Option<String>,   // --zsync / --repair のマニフェスト
+    repair: bool,                     // マニフェストを --repair として使う
+    concurrency: Arc<ConcurrencyLimit>,
+    host_limits: Arc<HostLimits>,             // --max-per-host
+    request_rate: Option<Arc<RateLimiter>>,   // --rate で新しいダウンロードを始める頻度を抑える
+    preflight: Option<Arc<ConcurrencyLimit>>, // --preflight の HEAD を同時に送る数の上限
+    tx: mpsc::Sender<Event>,
+    tasks: Vec<DownloadTask>, // 起動したすべてのタスク（スキップしたものを含む）
+    audit_log: Option<AuditLog>,
+    state_db: Option<StateDb>,                            // --state-db
+    open: bool,                         // 完了したファイルを既定のアプリケーションで開く
+    tar: Option<TarWriter<io::Stdout>>, // --tar-stdout の書き出し先
+    tar_next: usize,                    // 次に tar に書き出す tasks の位置
+    expected_checksums: Option<BTreeMap<String, String>>, // --expect-checksums のファイル名ごとの期待値
+}
+
+impl TaskQueue {
+    fn spawn(&mut self, downloads: &mut Downloads, task: DownloadTask) {
+        self.tasks.push(task.clone());
+        self.start(downloads, task);
+    }
+
+    /// 失敗したダウンロードを `.part` の続きから取り直す。表示名を返す
+    fn retry(&mut self, downloads: &mut Downloads, id: DownloadId) -> Option<String> {
+        let failed = downloads.errors.iter().position(|failed| failed.id == id)?;
+        let task = self.tasks.iter_mut().find(|task| task.id == id)?;
+        task.resume_from = fs::metadata(part_path(&task.path))
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let task = task.clone();
+        let name = downloads.errors.remove(failed).name;
+        downloads.outcomes.remove(&id);
+        self.start(downloads, task);
+        Some(name)
+    }
+
+    /// 記録済みのタスクを待機させてから起動する
+    fn start(&mut self, downloads: &mut Downloads, task: DownloadTask) {
+        let id = task.id;
+        let name = task
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        if self.skip_existing && task.path.exists() {
+            downloads.skipped.push(name);
+            downloads
+                .outcomes
+                .insert(id, Outcome::Skipped("保存先に既にあります".to_string()));
+            return;
+        }
+        if self
+            .state_db
+            .as_ref()
+            .is_some_and(|state_db| finished_before(state_db, &task))
+        {
+            downloads.skipped.push(name);
+            downloads.outcomes.insert(
+                id,
+                Outcome::Skipped("前回の実行で完了しています".to_string()),
+            );
+            return;
+        }
+        downloads.queue(id, &name, &task.url, task.resume_from);
+
+        let tx = self.tx.clone();
+        let options = self.options.clone();
+        let concat_urls = self.concat_urls.clone();
+        let zsync_manifest = self.zsync_manifest.clone();
+        let repair = self.repair;
+        let concurrency = Arc::clone(&self.concurrency);
+        let host_limits = Arc::clone(&self.host_limits);
+        let request_rate = self.request_rate.clone();
+        let preflight = self.preflight.clone();
+
+        tokio::spawn(async move {
+            // 保存できないと分かっている場合は枠を待たずに失敗させる
+            if let Err(e) = check_writable(&task.path) {
+                let _ = tx.send(Event::DownloadError(id, e));
+                return;
+            }
+            // HEAD はダウンロードの枠とは別の枠で送る。ダウンロードの枠を待つと、待機中のものの大きさが分からない
+            if let Some(preflight) = &preflight {
+                let size = {
+                    let _permit = preflight.acquire().await;
+                    preflight_size(&task.url, &options).await
+                };
+                if let Some(size) = size {
+                    let _ = tx.send(Event::DownloadSize(id, size));
+                }
+            }
+            // ホストの枠を先に取る。全体の枠を持ったまま待つと、ほかのホストのダウンロードまで止まる
+            let _host_permit = host_limits
+                .acquire(&host_dir(&task.url).unwrap_or_default())
+                .await;
+            let _permit = concurrency.acquire().await;
+            // 枠を確保してから待つ。先に待つと、枠の空きを待つ間に許可が溜まってまとめて始まってしまう
+            if let Some(request_rate) = &request_rate {
+                request_rate.acquire(1).await;
+            }
+            if let Some(pause) = &options.pause {
+                pause.wait().await;
+            }
+            let _ = tx.send(Event::DownloadStarted(id));
+            // 取得元は 1 つの URL だけで、失敗したときに別の取得元 (ミラーや torrent) へ切り替える仕組みはない。
+            // torrent を足すなら、その前に取得元の一覧を持たせて順に試す形にする
+            // --concat / --zsync は再試行しないので、制限時間はここでかける
+            let limit = options.timeout_for(&task.path);
+            let pause = options.pause.clone();
+            let result = match (concat_urls, zsync_manifest) {
+                (Some(urls), _) => limit_download_time(
+                    limit,
+                    pause,
+                    download_concat(task, urls, options, tx.clone()),
+                )
+                .await
+                .map_err(|e| e.to_string()),
+                (None, Some(manifest)) => limit_download_time(
+                    limit,
+                    pause,
+                    download_zsync(task, manifest, repair, options, tx.clone()),
+                )
+                .await
+                .map_err(|e| e.to_string()),
+                (None, None) => download_with_tries(task, options, tx.clone()).await,
+            };
+            if let Err(e) = result {
+                let _ = tx.send(Event::DownloadError(id, e));
+            }
+        });
+    }
+
+    /// --tar-stdout: 先頭のタスクから順に、結果の決まったものを tar に書き出す。
+    /// 前のタスクが終わるまで後のタスクは書き出さず、エントリの順序を URL の順に揃える。
+    /// 成功したファイルだけがエントリになる
+    fn stream_tar(&mut self, outcomes: &BTreeMap<DownloadId, Outcome>) -> io::Result<()> {
+        let Some(tar) = &mut self.tar else {
+            return Ok(());
+        };
+        for path in tar_ready(&self.tasks, outcomes, &mut self.tar_next) {
+            tar.append(path.to_path_buf())?;
+        }
+        Ok(())
+    }
+
+    /// 残りを書き出してアーカイブを閉じる。途中で中断したタスクより後ろは含めない
+    fn finish_tar(&mut self, outcomes: &BTreeMap<DownloadId, Outcome>) -> io::Result<()> {
+        self.stream_tar(outcomes)?;
+        if let Some(tar) = self.tar.take() {
+            tar.finish()?;
+        }
+        Ok(())
+    }
+
+    /// --expect-checksums の照合結果。指定していなければ空
+    fn integrity_report(&self, checksums: &BTreeMap<DownloadId, String>) -> Vec<String> {
+        match &self.expected_checksums {
+            Some(expected) => integrity_report(&self.tasks, checksums, expected),
+            None => Vec::new(),
+        }
+    }
+
+    /// --audit-log と --state-db に完了したダウンロードを記録する
+    fn record_done(&mut self, id: DownloadId, size: u64, sha256: Option<&str>) -> io::Result<()> {
+        self.record_state(id, StoredStatus::Done)?;
+        let (Some(audit_log), Some(task)) = (
+            &mut self.audit_log,
+            self.tasks.iter().find(|task| task.id == id),
+        ) else {
+            return Ok(());
+        };
+        audit_log.record(&task.url, &task.path, size, sha256)
+    }
+
+    /// --state-db に `id` の状態を書く
+    fn record_state(&self, id: DownloadId, status: StoredStatus) -> io::Result<()> {
+        let (Some(state_db), Some(task)) =
+            (&self.state_db, self.tasks.iter().find(|task| task.id == id))
+        else {
+            return Ok(());
+        };
+        state_db.put(&task.url, status)
+    }
+
+    /// --open: 完了したファイルを OS の既定のアプリケーションで開く。開けなければ警告文を返す
+    fn open_done(&self, id: DownloadId) -> Option<String> {
+        if !self.open {
+            return None;
+        }
+        let task = self.tasks.iter().find(|task| task.id == id)?;
+        if !has_display() {
+            return Some(format!("画面がないため開きません: {}", task.path.display()));
+        }
+        open::that_detached(&task.path)
+            .err()
+            .map(|e| format!("開けませんでした ({}): {}", task.path.display(), e))
+    }
+
+    /// 実行中に入力された URL をタスクにして起動し、保存するファイル名を返す
+    fn add_url(&mut self, downloads: &mut Downloads, input: &str) -> Result<String, String> {
+        if self.concat_urls.is_some() || self.zsync_manifest.is_some() {
+            return Err(
+                "--concat / --zsync / --repair の実行中は URL を追加できません".to_string(),
+            );
+        }
+        if self.options.gpg.as_ref().is_some_and(|gpg| gpg.is_fixed()) {
+            return Err(
+                "--verify-gpg に {url} を含まない署名を指定した場合は URL を追加できません"
+                    .to_string(),
+            );
+        }
+        let url = expand::expand_vars(input.trim())?;
+        reqwest::Url::parse(&url).map_err(|e| format!("不正な URL です: {e}"))?;
+        if self.tasks.iter().any(|task| task.url == url) {
+            return Err(format!("既に追加されている URL です: {url}"));
+        }
+        let name = saved_name(&url, self.options.decompress);
+        let id = self.tasks.iter().map(|task| task.id + 1).max().unwrap_or(0);
+        let taken: Vec<&Path> = self.tasks.iter().map(|task| task.path.as_path()).collect();
+        let path = self
+            .layout
+            .resolve_collision(self.layout.path(id, &url, &name), &taken)?;
+        let task = DownloadTask {
+            id,
+            url,
+            path,
+            resume_from: 0,
+        };
+        if let Some(parent) = task.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let name = task
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        self.spawn(downloads, task);
+        Ok(name)
+    }
+}
+
+/// --state-db: 前回の実行で完了し、保存したファイルも残っているか
+fn finished_before(state_db: &StateDb, task: &DownloadTask) -> bool {
+    matches!(state_db.get(&task.url), Ok(Some(StoredStatus::Done))) && task.path.exists()
+}
+
+/// --state-db: 記録があってまだ終わっていない URL は、残っている .part の続きから取得させる
+fn resume_unfinished(state_db: &StateDb, tasks: &mut [DownloadTask]) -> io::Result<()> {
+    for task in tasks {
+        let unfinished =
+            matches!(state_db.get(&task.url)?, Some(status) if status != StoredStatus::Done);
+        if unfinished && task.resume_from == 0 {
+            if let Ok(metadata) = fs::metadata(part_path(&task.path)) {
+                task.resume_from = metadata.len();
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn post_webhook(client: &reqwest::Client, url: &str, body: String) -> reqwest::Result<()> {
+    client
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// GUI のアプリケーションを起動できそうか。X11 / Wayland の環境では表示先の有無で判断する
+fn has_display() -> bool {
+    if cfg!(any(target_os = "macos", target_os = "windows")) {
+        return true;
+    }
+    std::env::var_os("DISPLAY").is_some() || std::env::var_os("WAYLAND_DISPLAY").is_some()
+}
+
+/// 完了したファイルのハッシュを `sha256sum -c` で検証できる形式で書き出す
+///
+/// パスはマニフェストのあるディレクトリからの相対パスにする。
+fn write_checksum_manifest(
+    manifest: &Path,
+    tasks: &[DownloadTask],
+    checksums: &BTreeMap<DownloadId, String>,
+) -> io::Result<()> {
+    let manifest = std::path::absolute(manifest)?;
+    let base = manifest.parent().unwrap_or(Path::new("/"));
+    let mut out = String::new();
+    for task in tasks {
+        let Some(sha256) = checksums.get(&task.id) else {
+            continue;
+        };
+        let path = std::path::absolute(&task.path)?;
+        let shown = path.strip_prefix(base).unwrap_or(&path);
+        out.push_str(&format!("{}  {}\n", sha256, shown.display()));
+    }
+    fs::write(&manifest, out)
+}
+
+/// --checksum-cache: 完了したファイルのハッシュを覚えておき、スキップしたファイルのハッシュをキャッシュから補う。
+/// 変わっていないファイルは読み直さないので、--skip-existing で繰り返し実行してもマニフェストから漏れない
+fn fill_checksums(
+    cache: &mut ChecksumCache,
+    tasks: &[DownloadTask],
+    outcomes: &BTreeMap<DownloadId, Outcome>,
+    checksums: &mut BTreeMap<DownloadId, String>,
+) -> io::Result<()> {
+    for task in tasks {
+        match (outcomes.get(&task.id), checksums.get(&task.id)) {
+            (Some(Outcome::Done), Some(sha256)) => cache.record(&task.path, sha256)?,
+            (Some(Outcome::Skipped(_)), None) if task.path.is_file() => {
+                checksums.insert(task.id, cache.sha256(&task.path)?);
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// SHA256SUMS 形式のファイルを読み、ファイル名から期待する SHA-256 を引けるようにする。
+/// `*name` (バイナリモード) の印やディレクトリの部分は無視する
+fn load_expected_checksums(path: &Path) -> Result<BTreeMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| eyre!("チェックサムを読み込めません ({}): {}", path.display(), e))?;
+    let mut expected = BTreeMap::new();
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let (sha256, name) = line
+            .split_once(char::is_whitespace)
+            .filter(|(sha256, _)| {
+                sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit())
+            })
+            .ok_or_else(|| {
+                eyre!(
+                    "SHA256SUMS 形式ではない行があります ({}): {}",
+                    path.display(),
+                    line
+                )
+            })?;
+        let entry = name.trim_start().trim_start_matches('*');
+        let name = Path::new(entry)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        // 保存するファイルとはファイル名だけで突き合わせるので、ディレクトリ違いの同名は区別できない
+        if expected
+            .insert(name.clone(), sha256.to_ascii_lowercase())
+            .is_some()
+        {
+            return Err(eyre!(
+                "ファイル名 {} の行が複数あります ({}): {}",
+                name,
+                path.display(),
+                entry
+            ));
+        }
+    }
+    Ok(expected)
+}
+
+/// --expect-checksums: ファイルごとに期待値と実際の SHA-256 を並べる。1 行目が件数の集計
+fn integrity_report(
+    tasks: &[DownloadTask],
+    checksums: &BTreeMap<DownloadId, String>,
+    expected: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut counts = [0; 4]; // 一致・不一致・期待値なし・未取得
+    let mut rows = Vec::new();
+    for task in tasks {
+        let name = task.path.file_name().unwrap_or_default().to_string_lossy();
+        let want = expected.get(name.as_ref());
+        let actual = checksums.get(&task.id);
+        let (index, status) = match (want, actual) {
+            (Some(want), Some(actual)) if want == actual => (0, "✓ 一致"),
+            (Some(_), Some(_)) => (1, "✗ 不一致"),
+            (None, Some(_)) => (2, "- 期待値なし"),
+            (_, None) => (3, "… 未取得"),
+        };
+        counts[index] += 1;
+        rows.push(format!(
+            "{}  {}  期待値 {}  実際 {}",
+            status,
+            task.path.display(),
+            want.map_or("-", String::as_str),
+            actual.map_or("-", String::as_str)
+        ));
+    }
+    let mut lines = vec![format!(
+        "整合性の確認: 一致 {} / 不一致 {} / 期待値なし {} / 未取得 {}",
+        counts[0], counts[1], counts[2], counts[3]
+    )];
+    lines.extend(rows);
+    lines
+}
+
+fn load_cookie_jar(path: &Path) -> Result<CookieStore> {
+    let reader = BufReader::new(File::open(path)?);
+    cookie_store::serde::json::load_all(reader)
+        .map_err(|e| eyre!("クッキージャーを読み込めません ({}): {}", path.display(), e))
+}
+
+/// セッションクッキーも含めて保存し、次回の実行でそのまま使えるようにする
+fn save_cookie_jar(path: &Path, store: &CookieStore) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    cookie_store::serde::json::save_incl_expired_and_nonpersistent(store, &mut writer)
+        .map_err(|e| eyre!("クッキージャーを保存できません ({}): {}", path.display(), e))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// クリップボードから改行区切りの URL を読み取る。URL でない行は警告して読み飛ばす
+fn urls_from_clipboard() -> Result<Vec<String>> {
+    let text = arboard::Clipboard::new()?.get_text()?;
+    let mut urls = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match reqwest::Url::parse(line) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => urls.push(line.to_string()),
+            _ => eprintln!("⚠ URL ではないため無視します: {}", line),
+        }
+    }
+    if urls.is_empty() {
+        return Err(eyre!("クリップボードに URL が見つかりません"));
+    }
+    Ok(urls)
+}
+
+/// URL のパス末尾からファイル名を決める
+fn filename_from_url(url: &str) -> String {
+    let name = reqwest::Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            url.path_segments()?
+                .rfind(|segment| !segment.is_empty())
+                .map(str::to_string)
+        })
+        .unwrap_or_default();
+    sanitize_filename(&name)
+}
+
+/// 保存するファイル名。--decompress では圧縮形式の拡張子を外す
+fn saved_name(url: &str, decompress: bool) -> String {
+    let name = filename_from_url(url);
+    if decompress {
+        Compression::strip_extension(&name)
+    } else {
+        name
+    }
+}
+
+/// 保存先のパスの決め方 (--output-dir / --organize-by-host / --output-template)
+struct OutputLayout {
+    dir: PathBuf,
+    organize_by_host: bool,
+    template: Option<OutputTemplate>,
+    date: String, // {date} に使う起動時の日付
+    collision: NameCollision,
+}
+
+/// 複数の URL が同じ保存先になったときの扱い
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NameCollision {
+    Overwrite, // 後のダウンロードで上書きする（既定）
+    Rename,    // --dedupe-names: "name (1).ext" のように番号を付ける
+    Error,     // --no-dedupe: エラーにする
+}
+
+impl OutputLayout {
+    fn new(cli: &DownloadArgs) -> Self {
+        Self {
+            dir: cli.output_dir.clone().unwrap_or_default(),
+            organize_by_host: cli.organize_by_host,
+            template: cli.output_template.clone(),
+            date: template::today(),
+            collision: if cli.dedupe_names {
+                NameCollision::Rename
+            } else if cli.no_dedupe {
+                NameCollision::Error
+            } else {
+                NameCollision::Overwrite
+            },
+        }
+    }
+
+    /// 既に使われている保存先 `taken` と重なったときの扱いを決める
+    fn resolve_collision(&self, path: PathBuf, taken: &[&Path]) -> Result<PathBuf, String> {
+        if !taken.contains(&path.as_path()) {
+            return Ok(path);
+        }
+        match self.collision {
+            NameCollision::Overwrite => Ok(path),
+            NameCollision::Error => Err(format!("保存先が重複しています: {}", path.display())),
+            NameCollision::Rename => {
+                let stem = path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned();
+                let ext = path
+                    .extension()
+                    .map(|ext| format!(".{}", ext.to_string_lossy()))
+                    .unwrap_or_default();
+                let renamed = (1..)
+                    .map(|n| path.with_file_name(format!("{} ({}){}", stem, n, ext)))
+                    .find(|candidate| !taken.contains(&candidate.as_path()))
+                    .unwrap();
+                Ok(renamed)
+            }
+        }
+    }
+
+    /// `id` 番目のダウンロードの保存先。テンプレートを展開した各要素も無害化し、保存先ディレクトリの外に出さない
+    fn path(&self, id: DownloadId, url: &str, name: &str) -> PathBuf {
+        let host = host_dir(url);
+        if let Some(template) = &self.template {
+            let rendered = template.render(&TemplateValues {
+                host: host.as_deref().unwrap_or("unknown"),
+                date: &self.date,
+                index: id + 1,
+                name,
+            });
+            return rendered
+                .split(['/', '\\'])
+                .filter(|component| !component.is_empty())
+                .fold(self.dir.clone(), |path, component| {
+                    path.join(sanitize_filename(component))
+                });
+        }
+        match host {
+            Some(host) if self.organize_by_host => self.dir.join(host).join(name),
+            _ => self.dir.join(name),
+        }
+    }
+}
+
+/// URL のホスト名（ポート指定があれば `host_port`）を安全なディレクトリ名にする
+fn host_dir(url: &str) -> Option<String> {
+    let url = reqwest::Url::parse(url).ok()?;
+    let host = url.host_str()?;
+    let name = match url.port() {
+        Some(port) => format!("{host}_{port}"),
+        None => host.to_string(),
+    };
+    Some(sanitize_filename(&name))
+}
+
+/// `.part` を付けてもファイルシステムの上限 (255 バイト) に収まる長さ
+const MAX_FILENAME_BYTES: usize = 240;
+
+/// サーバー由来のファイル名を保存先ディレクトリの外へ出られない安全な名前にする
+///
+/// ディレクトリ部分を取り除き、使えない文字を `_` に置き換え、長すぎる名前は
+/// 拡張子を残して切り詰める。空や `.`/`..` になる場合は "download" を返す。
+fn sanitize_filename(name: &str) -> String {
+    let base = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut cleaned: String = base
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    cleaned = cleaned.trim().trim_end_matches('.').to_string();
+    if cleaned.is_empty() || cleaned.chars().all(|c| c == '.') {
+        return "download".to_string();
+    }
+
+    if cleaned.len() > MAX_FILENAME_BYTES {
+        let ext = cleaned
+            .rfind('.')
+            .map(|dot| cleaned[dot..].to_string())
+            .filter(|ext| ext.len() <= 16)
+            .unwrap_or_default();
+        let mut end = MAX_FILENAME_BYTES - ext.len();
+        while !cleaned.is_char_boundary(end) {
+            end -= 1;
+        }
+        cleaned = format!("{}{}", &cleaned[..end], ext);
+    }
+    cleaned
+}
+
+fn input_handling(tx: mpsc::Sender<Event>) {
+    let tick_rate = Duration::from_millis(200);
+    thread::spawn(move || {
+        let mut last_tick = Instant::now();
+        loop {
+            let timeout = tick_rate.saturating_sub(last_tick.elapsed());
+            if event::poll(timeout).unwrap() {
+                match event::read().unwrap() {
+                    event::Event::Key(key) => tx.send(Event::Input(key)).unwrap(),
+                    event::Event::Resize(_, _) => tx.send(Event::Resize).unwrap(),
+                    _ => {}
+                }
+            }
+            if last_tick.elapsed() >= tick_rate {
+                tx.send(Event::Tick).unwrap();
+                last_tick = Instant::now();
+            }
+        }
+    });
+}
+
+/// ターミナルのタイトルに出す全体進捗
+fn overall_title(downloads: &Downloads) -> String {
+    let completed = downloads.completed.len();
+    let total = completed + downloads.in_progress.len();
+    format!(
+        "cli-tools {:.0}% ({}/{})",
+        overall_ratio(downloads) * 100.0,
+        completed,
+        total
+    )
+}
+
+/// 全体の進み具合。サイズが揃っていればバイト数、そうでなければ件数で割合を出す
+fn overall_ratio(downloads: &Downloads) -> f64 {
+    let completed = downloads.completed.len();
+    let total = completed + downloads.in_progress.len();
+    let sizes_known = downloads
+        .in_progress
+        .values()
+        .all(|download| download.total > 0);
+    let all_bytes: u64 = downloads.completed_bytes
+        + downloads
+            .in_progress
+            .values()
+            .map(|download| download.total)
+            .sum::<u64>();
+    if sizes_known && all_bytes > 0 {
+        let done: u64 = downloads.completed_bytes
+            + downloads
+                .in_progress
+                .values()
+                .map(|download| download.downloaded.min(download.total))
+                .sum::<u64>();
+        done as f64 / all_bytes as f64
+    } else if total > 0 {
+        completed as f64 / total as f64
+    } else {
+        0.0
+    }
+}
+
+/// --progress-heartbeat で書く機械向けの 1 行。bytes は完了分を含めて受信したバイト数
+fn heartbeat_line(downloads: &Downloads) -> String {
+    let bytes = downloads.completed_bytes
+        + downloads
+            .in_progress
+            .values()
+            .map(|download| download.downloaded)
+            .sum::<u64>();
+    format!(
+        "PROGRESS bytes={} pct={:.1} active={}",
+        bytes,
+        overall_ratio(downloads) * 100.0,
+        downloads.in_progress.len()
+    )
+}
+
+/// タイトルの全体進捗が `shown` から変わっていれば書き直す。受信のたびにエスケープ列を出さないようにする
+fn refresh_title(
+    out: &mut impl Write,
+    shown: &mut String,
+    downloads: &Downloads,
+) -> io::Result<()> {
+    let title = overall_title(downloads);
+    if title != *shown {
+        crossterm::execute!(out, crossterm::terminal::SetTitle(&title))?;
+        *shown = title;
+    }
+    Ok(())
+}
+
+/// UI を使わずにイベントを処理する (--quiet や TTY でない場合)。
+/// `errors_only` (--quiet-errors-only) では失敗したものをその場で標準エラー出力に書き出す。
+/// --set-title では、標準エラー出力が端末ならそこからタイトルを更新する
+fn run_quiet(
+    downloads: &mut Downloads,
+    queue: &mut TaskQueue,
+    rx: mpsc::Receiver<Event>,
+    errors_only: bool,
+) -> Result<()> {
+    let set_title = downloads.set_title && io::stderr().is_terminal();
+    let mut title = String::new();
+    while !downloads.in_progress.is_empty() {
+        match rx.recv()? {
+            Event::DownloadStarted(id) => {
+                downloads.start(id);
+                // 状態を記録できなくてもダウンロードは続けられるので、知らせるだけにする
+                if let Err(e) = queue.record_state(id, StoredStatus::Downloading) {
+                    eprintln!("⚠ ダウンロードの状態を記録できません: {}", e);
+                }
+            }
+            Event::DownloadSize(id, total) => downloads.set_total(id, total),
+            Event::DownloadVerifying(id) => downloads.set_status(id, Status::Verifying),
+            Event::DownloadVerifyUpdate(id, read, total) => {
+                downloads.verify_update(id, read, total)
+            }
+            Event::DownloadVerified(id, signer) => {
+                downloads.signers.insert(id, signer);
+            }
+            Event::DownloadRepaired(id, repaired) => {
+                if let Some(download) = downloads.in_progress.get(&id).filter(|_| !errors_only) {
+                    eprintln!("🔧 {}: {}を修復しました", download.name, repaired);
+                }
+                downloads.repairs.insert(id, repaired);
+            }
+            Event::DownloadUpdate(id, downloaded, total) => downloads.update(id, downloaded, total),
+            Event::DownloadDone(id, sha256) => {
+                if let Some(download) = downloads.finish(id, sha256) {
+                    // 記録に失敗してもダウンロード自体は済んでいるので、知らせて続ける
+                    if let Err(e) = queue.record_done(
+                        id,
+                        download.downloaded,
+                        downloads.checksums.get(&id).map(String::as_str),
+                    ) {
+                        eprintln!("⚠ {} の完了を記録できません: {}", download.name, e);
+                    }
+                    if let Some(warning) = queue.open_done(id) {
+                        eprintln!("⚠ {}", warning);
+                    }
+                }
+            }
+            Event::DownloadSkipped(id, reason) => {
+                downloads.skip(id, reason);
+            }
+            Event::DownloadError(id, error) => {
+                if downloads.fail(id, &error).is_some() {
+                    if let Err(e) = queue.record_state(id, StoredStatus::Failed) {
+                        eprintln!("⚠ ダウンロードの状態を記録できません: {}", e);
+                    }
+                    if let Some(failed) = downloads.errors.last().filter(|_| errors_only) {
+                        eprintln!(
+                            "❌ エラー: {} ({}) - {}",
+                            failed.name, failed.url, failed.message
+                        );
+                    }
+                }
+            }
+            Event::DumpProgress => {
+                for line in downloads.progress_snapshot() {
+                    eprintln!("{}", line);
+                }
+            }
+            Event::Heartbeat => eprintln!("{}", heartbeat_line(downloads)),
+            Event::DownloadWarning(id, warning) => {
+                if let Some(download) = downloads.in_progress.get(&id).filter(|_| !errors_only) {
+                    eprintln!("⚠ {}: {}", download.name, warning);
+                }
+            }
+            Event::DownloadMilestone(id, percent) => {
+                if let Some(download) = downloads.in_progress.get(&id).filter(|_| !errors_only) {
+                    eprintln!("{} {}%", download.name, percent);
+                }
+            }
+            Event::Input(_)
+            | Event::Tick
+            | Event::Resize
+            | Event::DownloadRetry(..)
+            | Event::DownloadReconnect(..) => {}
+        }
+        queue.stream_tar(&downloads.outcomes)?;
+        if set_title {
+            refresh_title(&mut io::stderr(), &mut title, downloads)?;
+        }
+    }
+    Ok(())
+}
+
+/// SIGUSR1 を受け取るたびに進捗の書き出しを要求する。ダウンロードには影響しない
+#[cfg(unix)]
+fn dump_progress_on_signal(tx: mpsc::Sender<Event>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut signals) = signal(SignalKind::user_defined1()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if tx.send(Event::DumpProgress).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn dump_progress_on_signal(_tx: mpsc::Sender<Event>) {}
+
+/// --progress-heartbeat: `interval` ごとに進捗行の書き出しを要求する
+fn progress_heartbeat(tx: mpsc::Sender<Event>, interval: Duration) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        loop {
+            ticks.tick().await;
+            if tx.send(Event::Heartbeat).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// --progress-heartbeat の進捗行を書く。`on_terminal` なら UI の上に差し込み、
+/// そうでなければリダイレクト先のログに 1 行ずつ書き出す
+fn print_heartbeat<B: Backend>(
+    terminal: &mut Terminal<B>,
+    downloads: &Downloads,
+    on_terminal: bool,
+) -> Result<()> {
+    let line = heartbeat_line(downloads);
+    if on_terminal {
+        terminal.insert_before(1, |buf| {
+            Paragraph::new(line).render(buf.area, buf);
+        })?;
+    } else {
+        eprintln!("{}", line);
+    }
+    Ok(())
+}
+
+fn print_all_done<B: Backend>(terminal: &mut Terminal<B>, downloads: &Downloads) -> Result<()> {
+    let mut spans = vec![
+        Span::styled("🎉 ", Style::default().fg(Color::Yellow)),
+        Span::styled(
+            "すべてのダウンロードが完了しました！",
+            Style::default()
+                .fg(Color::Green)
+                .add_modifier(Modifier::BOLD),
+        ),
+    ];
+    // 1 件ずつの行を出していなければ、最後に件数だけでも残す
+    if downloads.no_scrollback {
+        spans.push(Span::from(format!(
+            " (完了: {}件)",
+            downloads.completed.len()
+        )));
+    }
+    // --timestamping で手元が最新だったものは、ほかの理由のスキップと分けて数える
+    if downloads.up_to_date > 0 {
+        spans.push(Span::from(format!(" (最新: {}件)", downloads.up_to_date)));
+    }
+    if downloads.skipped.len() > downloads.up_to_date {
+        spans.push(Span::from(format!(
+            " (スキップ: {}件)",
+            downloads.skipped.len() - downloads.up_to_date
+        )));
+    }
+    if !downloads.errors.is_empty() {
+        spans.push(Span::styled(
+            format!(" (失敗: {}件)", downloads.errors.len()),
+            Style::default().fg(Color::Red),
+        ));
+    }
+    terminal.insert_before(1, |buf| {
+        Paragraph::new(Line::from(spans)).render(buf.area, buf);
+    })?;
+    Ok(())
+}
+
+fn run<B: Backend + Write>(
+    terminal: &mut Terminal<B>,
+    downloads: &mut Downloads,
+    queue: &mut TaskQueue,
+    rx: mpsc::Receiver<Event>,
+) -> Result<()> {
+    // 起動時にすべてスキップされていれば待つものはない
+    if downloads.in_progress.is_empty() {
+        downloads.integrity = queue.integrity_report(&downloads.checksums);
+        print_all_done(terminal, downloads)?;
+        if !downloads.wait_on_done {
+            return Ok(());
+        }
+    }
+
+    // 標準エラー出力が UI と同じ端末なら、直接書くと画面が崩れるので UI の上に差し込む
+    let heartbeat_on_terminal = io::stderr().is_terminal();
+    let mut redraw = true;
+    let mut clipboard: Option<arboard::Clipboard> = None;
+    let mut title = String::new();
+    loop {
+        if redraw {
+            terminal.draw(|frame| render(frame, downloads))?;
+            // タイトルは ratatui のバッファとは別に、描画が終わってから書き出す
+            if downloads.set_title {
+                refresh_title(terminal.backend_mut(), &mut title, downloads)?;
+            }
+        }
+        redraw = true;
+
+        match rx.recv()? {
+            // 入力欄が開いている間はキーを文字として扱う
+            Event::Input(event) if downloads.url_input.is_some() => {
+                let input = downloads.url_input.as_mut().unwrap();
+                match event.code {
+                    event::KeyCode::Char(c) => input.push(c),
+                    event::KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    event::KeyCode::Esc => downloads.url_input = None,
+                    event::KeyCode::Enter => {
+                        let input = downloads.url_input.take().unwrap_or_default();
+                        if input.trim().is_empty() {
+                            continue;
+                        }
+                        let line = match queue.add_url(downloads, &input) {
+                            Ok(name) => Line::from(vec![
+                                Span::from("＋ 追加: "),
+                                Span::styled(
+                                    name,
+                                    Style::default()
+                                        .add_modifier(Modifier::BOLD)
+                                        .fg(Color::Cyan),
+                                ),
+                            ]),
+                            Err(error) => Line::from(vec![
+                                Span::styled(
+                                    "❌ URL を追加できません",
+                                    Style::default().fg(Color::Red),
+                                ),
+                                Span::from(format!(" - {}", error)),
+                            ]),
+                        };
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(line).render(buf.area, buf);
+                        })?;
+                    }
+                    _ => {}
+                }
+            }
+            // 失敗一覧が開いている間は矢印キーで選び、r でその URL を取り直す
+            Event::Input(event) if downloads.error_panel.is_some() => match event.code {
+                event::KeyCode::Up => downloads.move_error_selection(false),
+                event::KeyCode::Down => downloads.move_error_selection(true),
+                event::KeyCode::Char('r') => {
+                    let selected = downloads.error_panel.unwrap_or_default();
+                    let Some(id) = downloads.errors.get(selected).map(|failed| failed.id) else {
+                        continue;
+                    };
+                    if let Some(name) = queue.retry(downloads, id) {
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(Line::from(vec![
+                                Span::from("↻ 再試行: "),
+                                Span::styled(
+                                    name,
+                                    Style::default()
+                                        .add_modifier(Modifier::BOLD)
+                                        .fg(Color::Cyan),
+                                ),
+                            ]))
+                            .render(buf.area, buf);
+                        })?;
+                    }
+                    downloads.error_panel = downloads
+                        .errors
+                        .len()
+                        .checked_sub(1)
+                        .map(|last| last.min(selected));
+                }
+                event::KeyCode::Char('e') | event::KeyCode::Esc => downloads.error_panel = None,
+                event::KeyCode::Char('q') => break,
+                _ => {}
+            },
+            // 設定パネルが開いている間は矢印キーで項目を選んで値を変える
+            Event::Input(event) if downloads.settings.is_some() => match event.code {
+                event::KeyCode::Up => {
+                    downloads.settings = downloads.settings.map(|setting| setting.moved(false))
+                }
+                event::KeyCode::Down => {
+                    downloads.settings = downloads.settings.map(|setting| setting.moved(true))
+                }
+                event::KeyCode::Left => downloads.adjust_setting(false),
+                event::KeyCode::Right => downloads.adjust_setting(true),
+                event::KeyCode::Char('s') | event::KeyCode::Esc => downloads.settings = None,
+                event::KeyCode::Char('q') => break,
+                _ => {}
+            },
+            Event::Input(event) => {
+                match event.code {
+                    event::KeyCode::Char('q') => break,
+                    event::KeyCode::Char('s') => downloads.settings = Some(Setting::Concurrency),
+                    event::KeyCode::Char('e') if !downloads.errors.is_empty() => {
+                        downloads.error_panel = Some(downloads.errors.len() - 1);
+                    }
+                    event::KeyCode::Char(' ') => {
+                        downloads.pause.toggle();
+                    }
+                    event::KeyCode::Up if downloads.finished() => {
+                        downloads.results_scroll = downloads.results_scroll.saturating_sub(1);
+                    }
+                    event::KeyCode::Down if downloads.finished() => {
+                        let results = downloads.completed.len()
+                            + downloads.skipped.len()
+                            + downloads.errors.len();
+                        downloads.results_scroll =
+                            (downloads.results_scroll + 1).min(results.saturating_sub(1) as u16);
+                    }
+                    event::KeyCode::Char('y') => {
+                        let Some((name, url)) = downloads.next_failed_url() else {
+                            continue;
+                        };
+                        // Linux では持ち主が居なくなると中身が消えるので、UI を閉じるまで持っておく
+                        let copied = match &mut clipboard {
+                            Some(clipboard) => Ok(clipboard),
+                            None => {
+                                arboard::Clipboard::new().map(|created| clipboard.insert(created))
+                            }
+                        }
+                        .and_then(|clipboard| clipboard.set_text(url.clone()));
+                        let line = match copied {
+                            Ok(()) => Line::from(vec![
+                                Span::from("📋 URL をコピーしました: "),
+                                Span::styled(
+                                    name,
+                                    Style::default()
+                                        .add_modifier(Modifier::BOLD)
+                                        .fg(Color::Cyan),
+                                ),
+                                Span::from(format!(" ({})", url)),
+                            ]),
+                            Err(e) => Line::from(vec![
+                                Span::styled(
+                                    "⚠ クリップボードにコピーできません",
+                                    Style::default().fg(Color::Yellow),
+                                ),
+                                Span::from(format!(" - {}", e)),
+                            ]),
+                        };
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(line).render(buf.area, buf);
+                        })?;
+                    }
+                    event::KeyCode::Char('a') => downloads.url_input = Some(String::new()),
+                    event::KeyCode::Char('v') => downloads.view_mode = downloads.view_mode.next(),
+                    event::KeyCode::Char('+') => {
+                        downloads
+                            .concurrency
+                            .set_limit(downloads.concurrency.limit() + 1);
+                    }
+                    event::KeyCode::Char('-') => {
+                        downloads
+                            .concurrency
+                            .set_limit(downloads.concurrency.limit().saturating_sub(1));
+                    }
+                    _ => {}
+                }
+            }
+            Event::Resize => {
+                terminal.autoresize()?;
+            }
+            Event::DownloadStarted(id) => {
+                downloads.start(id);
+                // 状態を記録できなくてもダウンロードは続けられるので、知らせるだけにする
+                if let Err(e) = queue.record_state(id, StoredStatus::Downloading) {
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Span::styled(
+                            format!("⚠ ダウンロードの状態を記録できません: {}", e),
+                            Style::default().fg(Color::Yellow),
+                        ))
+                        .render(buf.area, buf);
+                    })?;
+                }
+            }
+            Event::DownloadSize(id, total) => downloads.set_total(id, total),
+            Event::DownloadVerifying(id) => downloads.set_status(id, Status::Verifying),
+            Event::DownloadVerifyUpdate(id, read, total) => {
+                downloads.verify_update(id, read, total)
+            }
+            Event::DownloadVerified(id, signer) => {
+                downloads.signers.insert(id, signer);
+            }
+            Event::DownloadRepaired(id, repaired) => {
+                downloads.repairs.insert(id, repaired);
+            }
+            Event::Tick => {
+                downloads.tick = downloads.tick.wrapping_add(1);
+                if downloads.sparklines {
+                    downloads.record_speeds();
+                }
+            }
+            Event::DownloadUpdate(id, downloaded, total) => {
+                downloads.update(id, downloaded, total);
+                redraw = false;
+            }
+            Event::DownloadDone(id, sha256) => {
+                if let Some(download) = downloads.finish(id, sha256) {
+                    // 記録に失敗してもダウンロード自体は済んでいるので、知らせて続ける
+                    let record_warning = queue
+                        .record_done(
+                            id,
+                            download.downloaded,
+                            downloads.checksums.get(&id).map(String::as_str),
+                        )
+                        .err()
+                        .map(|e| format!("{} の完了を記録できません: {}", download.name, e));
+                    let duration = download.started_at.elapsed();
+                    let size_mb = download.total as f64 / 1_048_576.0;
+                    
+                    let mut spans = vec![
+                        Span::from("✓ ダウンロード完了: "),
+                        Span::styled(
+                            download.name.clone(),
+                            Style::default()
+                                .add_modifier(Modifier::BOLD)
+                                .fg(Color::Green),
+                        ),
+                        Span::from(format!(" ({:.2}MB, {}ms)", size_mb, duration.as_millis())),
+                    ];
+                    if let Some(signer) = downloads.signers.get(&id) {
+                        spans.push(Span::styled(
+                            format!(" (署名: {})", signer),
+                            Style::default().fg(Color::Green),
+                        ));
+                    }
+                    if let Some(repaired) = downloads.repairs.get(&id) {
+                        spans.push(Span::styled(
+                            format!(" (修復: {})", repaired),
+                            Style::default().fg(Color::Yellow),
+                        ));
+                    }
+                    // 空の本文で「成功」した場合は設定ミスの可能性があるので目立たせる
+                    if download.downloaded == 0 {
+                        spans.push(Span::styled(
+                            " (0 bytes)",
+                            Style::default().fg(Color::Yellow),
+                        ));
+                    }
+                    if !downloads.no_scrollback {
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(Line::from(spans)).render(buf.area, buf);
+                        })?;
+                    }
+                    for warning in record_warning.into_iter().chain(queue.open_done(id)) {
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(Span::styled(
+                                format!("⚠ {}", warning),
+                                Style::default().fg(Color::Yellow),
+                            ))
+                            .render(buf.area, buf);
+                        })?;
+                    }
+                    
+                    // --wait-on-done なら結果と失敗の一覧 (e) を見られるよう、q を押すまで閉じない
+                    if downloads.in_progress.is_empty() {
+                        downloads.integrity = queue.integrity_report(&downloads.checksums);
+                        print_all_done(terminal, downloads)?;
+                        if !downloads.wait_on_done {
+                            break;
+                        }
+                    }
+                }
+            }
+            Event::DownloadSkipped(id, reason) => {
+                if let Some(download) = downloads.skip(id, reason) {
+                    if !downloads.no_scrollback {
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(Line::from(vec![
+                                Span::from("⏭ スキップ: "),
+                                Span::styled(
+                                    download.name.clone(),
+                                    Style::default()
+                                        .add_modifier(Modifier::BOLD)
+                                        .fg(Color::Cyan),
+                                ),
+                                Span::from(format!(" ({})", reason)),
+                            ]))
+                            .render(buf.area, buf);
+                        })?;
+                    }
+
+                    // --wait-on-done なら結果と失敗の一覧 (e) を見られるよう、q を押すまで閉じない
+                    if downloads.in_progress.is_empty() {
+                        downloads.integrity = queue.integrity_report(&downloads.checksums);
+                        print_all_done(terminal, downloads)?;
+                        if !downloads.wait_on_done {
+                            break;
+                        }
+                    }
+                }
+            }
+            Event::DownloadError(id, error) => {
+                if let Some(download) = downloads.fail(id, &error) {
+                    let record_warning = queue
+                        .record_state(id, StoredStatus::Failed)
+                        .err()
+                        .map(|e| format!("ダウンロードの状態を記録できません: {}", e));
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Line::from(vec![
+                            Span::from("❌ エラー: "),
+                            Span::styled(
+                                download.name.clone(),
+                                Style::default().add_modifier(Modifier::BOLD).fg(Color::Red),
+                            ),
+                            Span::from(format!(" - {}", error)),
+                        ]))
+                        .render(buf.area, buf);
+                    })?;
+                    if let Some(warning) = record_warning {
+                        terminal.insert_before(1, |buf| {
+                            Paragraph::new(Span::styled(
+                                format!("⚠ {}", warning),
+                                Style::default().fg(Color::Yellow),
+                            ))
+                            .render(buf.area, buf);
+                        })?;
+                    }
+
+                    // 最後の 1 件が失敗で終わった場合も、完了と同じく結果を出して閉じる
+                    if downloads.in_progress.is_empty() {
+                        downloads.integrity = queue.integrity_report(&downloads.checksums);
+                        print_all_done(terminal, downloads)?;
+                        if !downloads.wait_on_done {
+                            break;
+                        }
+                    }
+                }
+            }
+            Event::DownloadRetry(id, attempt, error) => {
+                if let Some(download) = downloads.in_progress.get_mut(&id) {
+                    download.samples.clear();
+                    download.status = Status::Connecting;
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Line::from(vec![
+                            Span::from("↻ 再試行: "),
+                            Span::styled(
+                                download.name.clone(),
+                                Style::default()
+                                    .add_modifier(Modifier::BOLD)
+                                    .fg(Color::Yellow),
+                            ),
+                            Span::from(format!(" ({}/{}) - {}", attempt, downloads.tries, error)),
+                        ]))
+                        .render(buf.area, buf);
+                    })?;
+                }
+            }
+            Event::DownloadReconnect(id, count, limit, reason) => {
+                if let Some(download) = downloads.in_progress.get_mut(&id) {
+                    download.samples.clear();
+                    download.status = Status::Connecting;
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Line::from(vec![
+                            Span::from("↻ 再接続: "),
+                            Span::styled(
+                                download.name.clone(),
+                                Style::default()
+                                    .add_modifier(Modifier::BOLD)
+                                    .fg(Color::Yellow),
+                            ),
+                            Span::from(format!(" ({}/{}回目) - {}", count, limit, reason)),
+                        ]))
+                        .render(buf.area, buf);
+                    })?;
+                }
+            }
+            Event::DownloadWarning(id, warning) => {
+                if let Some(download) = downloads.in_progress.get(&id) {
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Line::from(vec![
+                            Span::from("⚠ 警告: "),
+                            Span::styled(
+                                download.name.clone(),
+                                Style::default()
+                                    .add_modifier(Modifier::BOLD)
+                                    .fg(Color::Yellow),
+                            ),
+                            Span::from(format!(" - {}", warning)),
+                        ]))
+                        .render(buf.area, buf);
+                    })?;
+                }
+            }
+            Event::DownloadMilestone(id, percent) => {
+                if let Some(download) = downloads.in_progress.get(&id) {
+                    terminal.insert_before(1, |buf| {
+                        Paragraph::new(Line::from(vec![
+                            Span::from("▸ "),
+                            Span::styled(
+                                download.name.clone(),
+                                Style::default().add_modifier(Modifier::BOLD),
+                            ),
+                            Span::from(format!(" {}%", percent)),
+                        ]))
+                        .render(buf.area, buf);
+                    })?;
+                }
+            }
+            // 進捗は画面に出ているので、UI ではシグナルを待ち受けない
+            Event::DumpProgress => {}
+            Event::Heartbeat => print_heartbeat(terminal, downloads, heartbeat_on_terminal)?,
+        }
+        queue.stream_tar(&downloads.outcomes)?;
+    }
+    Ok(())
+}
+
+fn render(frame: &mut Frame, downloads: &Downloads) {
+    let area = frame.area();
+
+    let block = Block::new().title(Line::from("📥 ファイルダウンロード進捗").centered());
+    frame.render_widget(block, area);
+
+    let vertical = Layout::vertical([
+        Constraint::Length(2), // 全体の進捗
+        Constraint::Length(3), // ヘッダー
+        Constraint::Min(4),    // ダウンロード詳細
+    ]).margin(1);
+    
+    let areas = vertical.split(area);
+    let progress_area = areas[0];
+    let header_area = areas[1];
+    let details_area = areas[2];
+
+    // 全体の進捗
+    let total_downloads = downloads.completed.len() + downloads.in_progress.len();
+    let completed_downloads = downloads.completed.len();
+    
+    let progress = if total_downloads > 0 {
+        completed_downloads as f64 / total_downloads as f64
+    } else {
+        0.0
+    };
+    
+    // サイズが 1 つも分からない間は比率のバーではなくスピナーと合計バイト数を出す
+    let all_sizes_unknown = !downloads.in_progress.is_empty()
+        && downloads
+            .in_progress
+            .values()
+            .all(|download| download.total == 0);
+    if all_sizes_unknown {
+        let received: u64 = downloads.completed_bytes
+            + downloads
+                .in_progress
+                .values()
+                .map(|download| download.downloaded)
+                .sum::<u64>();
+        let spinner = SPINNER_FRAMES[downloads.tick as usize % SPINNER_FRAMES.len()];
+        let overall_progress = Paragraph::new(Line::from(vec![
+            Span::styled(spinner, Style::default().fg(Color::Green)),
+            Span::from(format!(
+                " 全体進捗 {}/{} ({:.2}MB 受信)",
+                completed_downloads,
+                total_downloads,
+                received as f64 / 1_048_576.0
+            )),
+        ]));
+        frame.render_widget(overall_progress, progress_area);
+    } else {
+        let overall_progress = LineGauge::default()
+            .filled_style(Style::default().fg(Color::Green))
+            .label(format!(
+                "全体進捗 {}/{}",
+                completed_downloads, total_downloads
+            ))
+            .ratio(progress);
+        frame.render_widget(overall_progress, progress_area);
+    }
+
+    // ヘッダー情報
+    let header_text = if downloads.in_progress.is_empty() {
+        if downloads.completed.is_empty() {
+            "ダウンロード待機中..."
+        } else {
+            "ダウンロード完了"
+        }
+    } else {
+        "ダウンロード中..."
+    };
+    // 一時停止中はヘッダーの見出しを差し替えて目立たせる
+    let (header_text, header_style) = if downloads.pause.is_paused() {
+        (
+            "⏸ 一時停止中",
+            Style::default()
+                .fg(Color::Black)
+                .bg(Color::Yellow)
+                .add_modifier(Modifier::BOLD),
+        )
+    } else {
+        (
+            header_text,
+            Style::default()
+                .fg(Color::Cyan)
+                .add_modifier(Modifier::BOLD),
+        )
+    };
+    
+    if let Some(input) = &downloads.url_input {
+        // 入力欄はヘッダーの位置に重ねて表示する
+        let input_box = Paragraph::new(input.as_str())
+            .block(Block::bordered().title("URL を追加 (Enter: 追加 / Esc: キャンセル)"));
+        frame.render_widget(input_box, header_area);
+        let cursor_x = header_area.x + 1 + text_width(input) as u16;
+        frame.set_cursor_position((
+            cursor_x.min(header_area.right().saturating_sub(2)),
+            header_area.y + 1,
+        ));
+    } else {
+        let header = Paragraph::new(vec![
+            Line::from(vec![
+                Span::styled(header_text, header_style),
+                Span::styled(
+                    format!(
+                        "  同時実行数: {} (+/-で変更, a: 追加, v: 表示, s: 設定)",
+                        downloads.concurrency.limit()
+                    ),
+                    Style::default().fg(Color::DarkGray),
+                ),
+            ]),
+            status_summary(downloads),
+        ]);
+        frame.render_widget(header, header_area);
+    }
+
+    // 個別ダウンロードの詳細。--wait-on-done で終わった後は結果の一覧に切り替える
+    match downloads.view_mode {
+        _ if downloads.finished() => render_results(frame, details_area, downloads),
+        ViewMode::Full => render_full(frame, details_area, downloads),
+        ViewMode::Oneline => render_oneline(frame, details_area, downloads),
+        ViewMode::Table => render_table(frame, details_area, downloads),
+    }
+    if let Some(selected) = downloads.settings {
+        render_settings(frame, details_area, downloads, selected);
+    }
+    if let Some(selected) = downloads.error_panel {
+        render_errors(frame, details_area, downloads, selected);
+    }
+}
+
+/// 詳細の上に重ねる失敗一覧。1 件を名前・URL・理由の 3 行で出し、選んでいるものが見えるようにスクロールする
+fn render_errors(frame: &mut Frame, details_area: Rect, downloads: &Downloads, selected: usize) {
+    const ENTRY_LINES: u16 = 3;
+    let lines: Vec<Line> = downloads
+        .errors
+        .iter()
+        .enumerate()
+        .flat_map(|(index, failed)| {
+            let name_style = if index == selected {
+                Style::default().add_modifier(Modifier::REVERSED | Modifier::BOLD)
+            } else {
+                Style::default().add_modifier(Modifier::BOLD).fg(Color::Red)
+            };
+            [
+                Line::styled(format!(" {}", failed.name), name_style),
+                Line::styled(
+                    format!("   {}", failed.url),
+                    Style::default().fg(Color::DarkGray),
+                ),
+                Line::from(format!("   {}", failed.message)),
+            ]
+        })
+        .collect();
+    let inner_height = details_area.height.saturating_sub(2);
+    let scroll =
+        (selected as u16 * ENTRY_LINES).saturating_sub(inner_height.saturating_sub(ENTRY_LINES));
+    frame.render_widget(Clear, details_area);
+    frame.render_widget(
+        Paragraph::new(lines)
+            .scroll((scroll, 0))
+            .block(Block::bordered().title(format!(
+                "失敗 {}/{} (↑↓: 選択 / r: 再試行 / e: 閉じる)",
+                selected + 1,
+                downloads.errors.len()
+            ))),
+        details_area,
+    );
+}
+
+/// 詳細の上に重ねる設定パネル。選んでいる項目を反転表示する
+fn render_settings(
+    frame: &mut Frame,
+    details_area: Rect,
+    downloads: &Downloads,
+    selected: Setting,
+) {
+    let lines: Vec<Line> = Setting::ALL
+        .into_iter()
+        .map(|setting| {
+            let (label, value) = match setting {
+                Setting::Concurrency => ("同時実行数", downloads.concurrency.limit().to_string()),
+                Setting::TotalRate => (
+                    "合計帯域",
+                    match downloads.total_rate.rate() as u64 {
+                        0 => "無制限".to_string(),
+                        rate => format!("{}/s", HumanBytes(rate)),
+                    },
+                ),
+                Setting::PerHost => (
+                    "ホストごとの上限",
+                    match downloads.host_limits.limit() {
+                        0 => "無制限".to_string(),
+                        limit => limit.to_string(),
+                    },
+                ),
+            };
+            let style = if setting == selected {
+                Style::default().add_modifier(Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+            Line::styled(format!(" {}: ◀ {} ▶", label, value), style)
+        })
+        .collect();
+    let area = Rect {
+        height: (lines.len() as u16 + 2).min(details_area.height),
+        ..details_area
+    };
+    frame.render_widget(Clear, area);
+    frame.render_widget(
+        Paragraph::new(lines)
+            .block(Block::bordered().title("設定 (↑↓: 選択 / ←→: 変更 / s: 閉じる)")),
+        area,
+    );
+}
+
+/// --wait-on-done で終わった後の結果の一覧。完了・スキップ・失敗の順に並べる
+fn render_results(frame: &mut Frame, details_area: Rect, downloads: &Downloads) {
+    let completed = downloads
+        .completed
+        .iter()
+        .map(|name| Line::styled(format!(" ✓ {}", name), Style::default().fg(Color::Green)));
+    let skipped = downloads
+        .skipped
+        .iter()
+        .map(|name| Line::styled(format!(" ⏭ {}", name), Style::default().fg(Color::DarkGray)));
+    let failed = downloads.errors.iter().map(|failed| {
+        Line::styled(
+            format!(" ✗ {} - {}", failed.name, failed.message),
+            Style::default().fg(Color::Red),
+        )
+    });
+    // --expect-checksums の照合結果は一覧の後ろに空行を挟んで続ける
+    let integrity = downloads.integrity.iter().enumerate().map(|(index, line)| {
+        let color = match line.chars().next() {
+            _ if index == 0 => Color::Reset,
+            Some('✓') => Color::Green,
+            Some('✗') => Color::Red,
+            _ => Color::DarkGray,
+        };
+        Line::styled(format!(" {}", line), Style::default().fg(color))
+    });
+    let separator = (!downloads.integrity.is_empty()).then(Line::default);
+    let lines: Vec<Line> = completed
+        .chain(skipped)
+        .chain(failed)
+        .chain(separator)
+        .chain(integrity)
+        .collect();
+    // 最後の行が下端に来るところより先にはスクロールしない
+    let max_scroll = (lines.len() as u16).saturating_sub(details_area.height.saturating_sub(2));
+    frame.render_widget(
+        Paragraph::new(lines)
+            .scroll((downloads.results_scroll.min(max_scroll), 0))
+            .block(Block::bordered().title("結果 (↑↓: スクロール / q: 終了)")),
+        details_area,
+    );
+}
+
+/// 状態ごとの件数を「⏳ 待機 3  ⬇ 受信 2  ✓ 完了 5  ✗ 失敗 1」のように並べる。0 件の状態は省く
+fn status_summary(downloads: &Downloads) -> Line<'static> {
+    let count = |status| {
+        downloads
+            .in_progress
+            .values()
+            .filter(|download| download.status == status)
+            .count()
+    };
+    let counts = [
+        ("⏳ 待機", count(Status::Queued), Color::DarkGray),
+        ("⇄ 接続", count(Status::Connecting), Color::Yellow),
+        ("⬇ 受信", count(Status::Downloading), Color::Cyan),
+        ("🔏 検証", count(Status::Verifying), Color::Magenta),
+        ("✓ 完了", downloads.completed.len(), Color::Green),
+        ("⏭ スキップ", downloads.skipped.len(), Color::DarkGray),
+        ("✗ 失敗", downloads.errors.len(), Color::Red),
+    ];
+    let mut spans = Vec::new();
+    for (label, count, color) in counts {
+        if count == 0 {
+            continue;
+        }
+        if !spans.is_empty() {
+            spans.push(Span::from("  "));
+        }
+        spans.push(Span::styled(
+            format!("{label} {count}"),
+            Style::default().fg(color),
+        ));
+    }
+    if !downloads.errors.is_empty() {
+        spans.push(Span::styled(
+            "  (e: 失敗の詳細, y: URL をコピー)",
+            Style::default().fg(Color::DarkGray),
+        ));
+    }
+    Line::from(spans)
+}
+
+/// 速度の表示。bits では回線の速度表記に合わせて 10 進の Mbps にする
+fn format_speed(bytes_per_sec: f64, units: SpeedUnit) -> String {
+    match units {
+        SpeedUnit::Bytes => format!("{:.2}MB/s", bytes_per_sec / 1_048_576.0),
+        SpeedUnit::Bits => format!("{:.2}Mbps", bytes_per_sec * 8.0 / 1_000_000.0),
+    }
+}
+
+/// サーバーが圧縮して返すと書き込んだ (展開後の) バイト数が Content-Length を超えるので 100% で止める
+fn progress_label(download: &DownloadInProgress, kind: GaugeLabel) -> (f64, String) {
+    let progress_ratio = (download.progress() / 100.0).min(1.0);
+    let percent = format!("{:.1}%", progress_ratio * 100.0);
+    let bytes = if download.total > 0 {
+        format!(
+            "{}/{}",
+            HumanBytes(download.downloaded),
+            HumanBytes(download.total)
+        )
+    } else {
+        HumanBytes(download.downloaded).to_string()
+    };
+    let mut label = match kind {
+        GaugeLabel::Percent => percent,
+        GaugeLabel::Bytes => bytes,
+        GaugeLabel::Both => format!("{bytes} ({percent})"),
+    };
+    if download.is_decompressed() {
+        label.push_str(" (展開後)");
+    }
+    (progress_ratio, label)
+}
+
+/// Gauge はラベルを中央に置くので、左を空白で埋めてバーの幅いっぱいにし右寄せにする。
+/// バイト数が入りきらない幅では割合だけに切り替える
+fn gauge_label(download: &DownloadInProgress, kind: GaugeLabel, width: u16) -> String {
+    let (_, label) = progress_label(download, kind);
+    if kind == GaugeLabel::Percent {
+        return label;
+    }
+    let width = usize::from(width);
+    let mut label_width = text_width(&label);
+    let label = if label_width < width {
+        label
+    } else {
+        let (_, percent) = progress_label(download, GaugeLabel::Percent);
+        label_width = text_width(&percent);
+        percent
+    };
+    // 右端に 1 桁の余白を残す
+    let padding = width.saturating_sub(label_width + 1);
+    format!("{}{label} ", " ".repeat(padding))
+}
+
+/// --show-url で URL を付けるのに最低限必要な幅。これより狭ければ URL は出さない
+const MIN_URL_WIDTH: usize = 16;
+
+/// 情報行の内容。幅に収まらなければ速度と残り時間を省いて名前と割合だけにし、
+/// それでも収まらなければ名前を切り詰める。`show_url` の URL は余った幅にだけ出す。
+/// 受信中でなければ統計は出さず、待っている間はスピナーを付ける。速度は `speed_color` で色付けする
+fn info_text(
+    download: &DownloadInProgress,
+    width: u16,
+    show_url: bool,
+    spinner: &str,
+    speed_color: Color,
+    units: SpeedUnit,
+) -> Vec<Span<'static>> {
+    let plain = |text: String| Span::styled(text, Style::default().fg(Color::White));
+    match download.status {
+        Status::Queued => return vec![plain(fit_name("📦 ", &download.name, "", width))],
+        Status::Connecting | Status::Verifying => {
+            let suffix = download
+                .verify_label()
+                .map(|(ratio, _)| format!(" (検証 {:.1}%)", ratio * 100.0))
+                .unwrap_or_default();
+            return vec![plain(fit_name(
+                &format!("{spinner} "),
+                &download.name,
+                &suffix,
+                width,
+            ))];
+        }
+        Status::Downloading | Status::Paused => {}
+    }
+    let downloaded_mb = download.downloaded as f64 / 1_048_576.0;
+    let total_mb = download.total as f64 / 1_048_576.0;
+    let speed = format_speed(download.speed(), units);
+    let eta = download
+        .eta()
+        .map(format_eta)
+        .unwrap_or_else(|| "--".to_string());
+
+    let (mut full, compact) = if download.total > 0 {
+        let (_, percent) = progress_label(download, GaugeLabel::Percent);
+        (
+            vec![
+                plain(format!(
+                    "📦 {} ({:.2}/{:.2}MB, ",
+                    download.name, downloaded_mb, total_mb
+                )),
+                Span::styled(speed, Style::default().fg(speed_color)),
+                plain(format!(", 残り {})", eta)),
+            ],
+            format!("({percent})"),
+        )
+    } else {
+        (
+            vec![plain(format!(
+                "📦 {} ({:.2}MB, サイズ不明)",
+                download.name, downloaded_mb
+            ))],
+            format!("({:.2}MB)", downloaded_mb),
+        )
+    };
+    let used: usize = full.iter().map(Span::width).sum();
+    if used <= usize::from(width) {
+        let available = usize::from(width).saturating_sub(used + 1);
+        if show_url && available >= MIN_URL_WIDTH {
+            full.push(plain(format!(
+                " {}",
+                truncate_middle(&download.url, available)
+            )));
+        }
+        return full;
+    }
+    vec![plain(fit_name(
+        "📦 ",
+        &download.name,
+        &format!(" {compact}"),
+        width,
+    ))]
+}
+
+/// ピークに近い速度は緑、半分を切ると黄、5 分の 1 を切ると赤にする
+fn speed_color(speed: f64, peak: f64) -> Color {
+    if peak <= 0.0 {
+        return Color::White;
+    }
+    let ratio = speed / peak;
+    if ratio >= 0.5 {
+        Color::Green
+    } else if ratio >= 0.2 {
+        Color::Yellow
+    } else {
+        Color::Red
+    }
+}
+
+/// 端末上の表示幅。全角文字や絵文字は 2 桁と数える
+fn text_width(text: &str) -> usize {
+    text.width()
+}
+
+/// `prefix` と `suffix` を残し、間の `name` を幅に収まるよう末尾を「…」にして切り詰める
+fn fit_name(prefix: &str, name: &str, suffix: &str, width: u16) -> String {
+    let available = usize::from(width).saturating_sub(text_width(prefix) + text_width(suffix));
+    format!("{prefix}{}{suffix}", truncate_name(name, available))
+}
+
+/// 表示幅で数えて書記素クラスタ単位で切り詰めるので、結合文字や絵文字の途中では切らない
+fn truncate_name(name: &str, max_width: usize) -> String {
+    if text_width(name) <= max_width {
+        return name.to_string();
+    }
+    let budget = max_width.saturating_sub(1); // 「…」の分
+    let mut truncated = String::new();
+    let mut width = 0;
+    for grapheme in name.graphemes(true) {
+        let grapheme_width = text_width(grapheme);
+        if width + grapheme_width > budget {
+            break;
+        }
+        width += grapheme_width;
+        truncated.push_str(grapheme);
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// ホスト名と末尾のファイル名が残るよう、中央を「…」にして幅に収める
+fn truncate_middle(text: &str, max_width: usize) -> String {
+    if text_width(text) <= max_width {
+        return text.to_string();
+    }
+    // 「…」の分を除いた幅
+    let budget = max_width.saturating_sub(1);
+    // 幅 `limit` に収まる先頭からの書記素クラスタの数
+    fn fitting<'a>(graphemes: impl Iterator<Item = &'a &'a str>, limit: usize) -> usize {
+        let mut width = 0;
+        graphemes
+            .take_while(|grapheme| {
+                width += text_width(grapheme);
+                width <= limit
+            })
+            .count()
+    }
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+    let head = fitting(graphemes.iter(), budget.div_ceil(2));
+    let tail = fitting(graphemes.iter().rev(), budget / 2);
+    format!(
+        "{}…{}",
+        graphemes[..head].concat(),
+        graphemes[graphemes.len() - tail..].concat()
+    )
+}
+
+/// 1 ダウンロードにつき情報行と進捗バーの 3 行を使う表示 (--no-progress では情報行のみ)
+fn render_full(frame: &mut Frame, details_area: Rect, downloads: &Downloads) {
+    // 進捗バーの下の空行を --sparklines のグラフに使う
+    let rows = match (downloads.no_progress, downloads.sparklines) {
+        (true, false) => 1,
+        (true, true) => 2,
+        (false, _) => 3,
+    };
+    let spinner = SPINNER_FRAMES[downloads.tick as usize % SPINNER_FRAMES.len()];
+    let mut y_offset = 0;
+    for (_, download) in downloads.in_progress.iter() {
+        if y_offset + rows > details_area.height {
+            break;
+        }
+
+        // ファイル名と統計情報
+        let info_area = Rect {
+            x: details_area.x,
+            y: details_area.y + y_offset,
+            width: details_area.width,
+            height: 1,
+        };
+        
+        let tag = downloads.status_of(download).tag();
+        let text_width = info_area.width.saturating_sub(tag.width() as u16 + 1);
+        let speed_color = speed_color(download.speed(), downloads.peak_speed);
+        let mut spans = vec![tag, Span::raw(" ")];
+        spans.extend(info_text(
+            download,
+            text_width,
+            downloads.show_url,
+            spinner,
+            speed_color,
+            downloads.units,
+        ));
+        let info = Paragraph::new(Line::from(spans));
+        frame.render_widget(info, info_area);
+
+        if downloads.sparklines {
+            let sparkline_area = Rect {
+                x: details_area.x,
+                y: details_area.y + y_offset + rows - 1,
+                width: details_area.width,
+                height: 1,
+            };
+            // 幅に収まる直近の分だけを描く
+            let skip = download
+                .speed_history
+                .len()
+                .saturating_sub(usize::from(sparkline_area.width));
+            let history: Vec<u64> = download.speed_history.iter().skip(skip).copied().collect();
+            let sparkline = Sparkline::default()
+                .data(&history)
+                .style(Style::default().fg(Color::Cyan));
+            frame.render_widget(sparkline, sparkline_area);
+        }
+
+        if downloads.no_progress {
+            y_offset += rows;
+            continue;
+        }
+
+        // 進捗バー
+        let gauge_area = Rect {
+            x: details_area.x,
+            y: details_area.y + y_offset + 1,
+            width: details_area.width,
+            height: 1,
+        };
+
+        // 検証で読み返している間は、受信の進捗に代えて読み返しの進捗を別の色で出す
+        let (progress_ratio, label, color) = match download.verify_label() {
+            Some((ratio, label)) => (ratio, label, Color::Magenta),
+            None => (
+                (download.progress() / 100.0).min(1.0),
+                gauge_label(download, downloads.gauge_label, gauge_area.width),
+                Color::Yellow,
+            ),
+        };
+        let gauge = Gauge::default()
+            .gauge_style(Style::default().fg(color))
+            .percent((progress_ratio * 100.0) as u16)
+            .label(label);
+        
+        frame.render_widget(gauge, gauge_area);
+        
+        y_offset += rows;
+    }
+}
+
+/// 1 ダウンロードを 1 行の LineGauge にまとめる表示
+fn render_oneline(frame: &mut Frame, details_area: Rect, downloads: &Downloads) {
+    for (row, download) in downloads.in_progress.values().enumerate() {
+        let row = row as u16;
+        if row >= details_area.height {
+            break;
+        }
+
+        let line_area = Rect {
+            x: details_area.x,
+            y: details_area.y + row,
+            width: details_area.width,
+            height: 1,
+        };
+
+        // 幅が足りなければ速度を省き、それでも足りなければ名前を切り詰める
+        let tag = downloads.status_of(download).tag();
+        let width = line_area.width.saturating_sub(tag.width() as u16 + 1);
+        let (progress_ratio, label) = download
+            .verify_label()
+            .unwrap_or_else(|| progress_label(download, downloads.gauge_label));
+        let speed = format_speed(download.speed(), downloads.units);
+        let full = format!("{} {} ", download.name, label);
+        let mut spans = vec![tag, Span::raw(" ")];
+        match download.status {
+            Status::Queued => {
+                spans.push(Span::raw(truncate_name(&download.name, usize::from(width))))
+            }
+            Status::Verifying if download.verify_progress.is_some() => {
+                spans.push(Span::raw(fit_name(
+                    "",
+                    &download.name,
+                    &format!(" {label}"),
+                    width,
+                )));
+            }
+            Status::Connecting | Status::Verifying => {
+                let spinner = SPINNER_FRAMES[downloads.tick as usize % SPINNER_FRAMES.len()];
+                spans.push(Span::raw(fit_name(
+                    "",
+                    &download.name,
+                    &format!(" {spinner}"),
+                    width,
+                )));
+            }
+            Status::Downloading | Status::Paused
+                if text_width(&full) + text_width(&speed) <= usize::from(width) =>
+            {
+                let color = speed_color(download.speed(), downloads.peak_speed);
+                spans.push(Span::raw(full));
+                spans.push(Span::styled(speed, Style::default().fg(color)));
+            }
+            Status::Downloading | Status::Paused => {
+                spans.push(Span::raw(fit_name(
+                    "",
+                    &download.name,
+                    &format!(" {label}"),
+                    width,
+                )));
+            }
+        }
+        let label = Line::from(spans);
+        if downloads.no_progress {
+            frame.render_widget(Paragraph::new(label), line_area);
+        } else {
+            let color = if download.verify_label().is_some() {
+                Color::Magenta
+            } else {
+                Color::Yellow
+            };
+            let gauge = LineGauge::default()
+                .filled_style(Style::default().fg(color))
+                .label(label)
+                .ratio(progress_ratio);
+            frame.render_widget(gauge, line_area);
+        }
+    }
+}
+
+/// 1 ダウンロードを表の 1 行にまとめる表示
+fn render_table(frame: &mut Frame, details_area: Rect, downloads: &Downloads) {
+    let header = Row::new(["状態", "ファイル", "進捗", "速度", "残り"])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+    let rows = downloads.in_progress.values().map(|download| {
+        let progress = if let Some((ratio, _)) = download.verify_label() {
+            format!("{:.1}%", ratio * 100.0)
+        } else if download.total > 0 {
+            progress_label(download, GaugeLabel::Percent).1
+        } else {
+            format!("{:.2}MB", download.downloaded as f64 / 1_048_576.0)
+        };
+        let speed = format_speed(download.speed(), downloads.units);
+        let eta = download
+            .eta()
+            .map(format_eta)
+            .unwrap_or_else(|| "--".to_string());
+        Row::new([
+            Cell::from(downloads.status_of(download).tag()),
+            Cell::from(download.name.clone()),
+            Cell::from(progress),
+            Cell::from(Span::styled(
+                speed,
+                Style::default().fg(speed_color(download.speed(), downloads.peak_speed)),
+            )),
+            Cell::from(eta),
+        ])
+    });
+    let widths = [
+        Constraint::Length(6),
+        Constraint::Fill(1),
+        Constraint::Length(9),
+        Constraint::Length(11),
+        Constraint::Length(7),
+    ];
+    frame.render_widget(Table::new(rows, widths).header(header), details_area);
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    color_eyre::install()?;
+    match Cli::parse().into_command() {
+        Command::Download(args) => download_command(args).await,
+    }
+}
+
+/// `download` サブコマンド
+async fn download_command(mut cli: DownloadArgs) -> Result<()> {
+    if cli.from_clipboard {
+        cli.urls.extend(urls_from_clipboard()?);
+    }
+    if (cli.zsync.is_some() || cli.repair.is_some()) && cli.urls.len() != 1 {
+        return Err(eyre!(
+            "--zsync / --repair ではダウンロードする URL を 1 つだけ指定してください"
+        ));
+    }
+    // 帯域の上限が --min-rate より低いと、どのダウンロードも遅すぎるとして再接続を繰り返す
+    if let (Some(min_rate), Some(max_total_rate)) = (cli.min_rate, cli.max_total_rate) {
+        if max_total_rate > 0 && max_total_rate < min_rate {
+            return Err(eyre!(
+                "--max-total-rate ({}) が --min-rate ({}) より低いため、すべてのダウンロードが遅すぎると判定されます",
+                max_total_rate,
+                min_rate
+            ));
+        }
+    }
+    let gpg = match (&cli.verify_gpg, &cli.gpg_keyring) {
+        (Some(signature), Some(keyring)) => Some(Arc::new(
+            Verifier::new(signature.clone(), keyring).map_err(|e| eyre!(e))?,
+        )),
+        _ => None,
+    };
+    // 読めないチェックサムのファイルはダウンロードを始める前にエラーにする
+    let expected_checksums = cli
+        .expect_checksums
+        .as_deref()
+        .map(load_expected_checksums)
+        .transpose()?;
+    // --concat は連結した 1 ファイルを検証するので URL がいくつあってもよい
+    if gpg.as_ref().is_some_and(|gpg| gpg.is_fixed()) && !cli.concat && cli.urls.len() != 1 {
+        return Err(eyre!(
+            "--verify-gpg の署名に {{url}} を含めない場合は URL を 1 つだけ指定してください"
+        ));
+    }
+
+    let sources: Vec<(String, String)> = if let Some(output) = &cli.output {
+        // --concat では全 URL をまとめて 1 つのタスクとして扱う
+        vec![(cli.urls[0].clone(), output.to_string_lossy().into_owned())]
+    } else if cli.urls.is_empty() {
+        // 複数のファイルをダウンロードするサンプル
+        vec![
+            (
+                "http://archive.ubuntu.com/ubuntu/pool/universe/b/bmon/bmon_4.0-6_amd64.deb"
+                    .to_string(),
+                "bmon.deb".to_string(),
+            ),
+            (
+                "https://httpbin.org/bytes/1024".to_string(),
+                "sample1.bin".to_string(),
+            ),
+            (
+                "https://httpbin.org/bytes/2048".to_string(),
+                "sample2.bin".to_string(),
+            ),
+        ]
+    } else {
+        cli.urls
+            .iter()
+            .map(|url| (url.clone(), saved_name(url, cli.decompress)))
+            .collect()
+    };
+
+    let mut layout = OutputLayout::new(&cli);
+    // --tar-stdout では作業ディレクトリに保存し、tar に書き出したものから消す
+    let tar = if cli.tar_stdout {
+        let staging = std::env::temp_dir().join(format!("cli-tools-tar-{}", std::process::id()));
+        layout.dir = staging.clone();
+        Some(TarWriter::spawn(TarStream::new(io::stdout(), staging)?))
+    } else {
+        None
+    };
+    if cli.output_dir.is_some() {
+        fs::create_dir_all(&layout.dir)?;
+    }
+
+    let mut download_tasks: Vec<DownloadTask> = Vec::new();
+    for (id, (url, name)) in sources.into_iter().enumerate() {
+        let path = layout.path(id, &url, &name);
+        // 同じ URL を同じ保存先に重ねて取得しても、同じファイルを書き合うだけなので 1 つにまとめる
+        if download_tasks
+            .iter()
+            .any(|task| task.url == url && task.path == path)
+        {
+            eprintln!("⚠ 重複した URL を除きます: {}", url);
+            continue;
+        }
+        let taken: Vec<&Path> = download_tasks
+            .iter()
+            .map(|task| task.path.as_path())
+            .collect();
+        let path = layout
+            .resolve_collision(path, &taken)
+            .map_err(|e| eyre!("{} ({})", e, url))?;
+        // 上書きする場合は結果が後の URL のものになるので、書き込みが重ならないよう前の URL を取得しない
+        if layout.collision == NameCollision::Overwrite {
+            download_tasks.retain(|task| {
+                let overwritten = task.path == path;
+                if overwritten {
+                    eprintln!(
+                        "⚠ 保存先が同じ後の URL で上書きされるためスキップします: {}",
+                        task.url
+                    );
+                }
+                !overwritten
+            });
+        }
+        download_tasks.push(DownloadTask {
+            id,
+            url,
+            path,
+            resume_from: 0,
+        });
+    }
+    for task in &download_tasks {
+        if let Some(parent) = task.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    // --cookie / --cookie-jar があればクッキーストアを有効にし、リダイレクト先にも引き継ぐ
+    let cookie_store = if cli.cookie_jar.is_some() || !cli.cookies.is_empty() {
+        let store = match &cli.cookie_jar {
+            Some(path) if path.exists() => load_cookie_jar(path)?,
+            _ => CookieStore::default(),
+        };
+        Some(Arc::new(CookieStoreMutex::new(store)))
+    } else {
+        None
+    };
+    if let Some(store) = &cookie_store {
+        let mut store = store.lock().unwrap();
+        for task in &download_tasks {
+            let Ok(url) = reqwest::Url::parse(&task.url) else {
+                continue;
+            };
+            for cookie in &cli.cookies {
+                store.insert_raw(&RawCookie::parse(cookie.as_str())?, &url)?;
+            }
+        }
+    }
+    let mut client = reqwest::Client::builder();
+    if let Some(store) = &cookie_store {
+        client = client.cookie_provider(Arc::clone(store));
+    }
+    // 指定がなければ通常の TCP 接続のまま
+    if let Some(socket) = &cli.unix_socket {
+        #[cfg(unix)]
+        {
+            client = client.unix_socket(socket.clone());
+        }
+        #[cfg(not(unix))]
+        return Err(eyre!(
+            "--unix-socket はこのプラットフォームでは使えません: {}",
+            socket.display()
+        ));
+    }
+    for (host, addr) in &cli.resolves {
+        client = client.resolve(host, *addr);
+    }
+    if let Some(max_idle) = cli.pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    if let Some(secs) = cli.pool_idle_timeout {
+        client = client.pool_idle_timeout(Duration::from_secs_f64(secs));
+    }
+    // IPv6 と IPv4 の接続を競わせる Happy Eyeballs の待ち時間は reqwest の HttpConnector で 300ms に固定されており、
+    // ClientBuilder からは変えられない。調整できるようにするには独自のコネクターが要るため、今は既定のままにしている
+    if cli.http2_prior_knowledge {
+        client = client.http2_prior_knowledge();
+    }
+    if cli.http3 {
+        #[cfg(feature = "http3")]
+        {
+            client = client.http3_prior_knowledge();
+        }
+        #[cfg(not(feature = "http3"))]
+        return Err(eyre!("--http3 を使うには http3 feature を有効にしてビルドしてください（reqwest の都合で RUSTFLAGS=\"--cfg reqwest_unstable\" も必要です）"));
+    }
+
+    let body = match (&cli.data, &cli.data_file) {
+        (Some(data), _) => Some(data.clone().into_bytes()),
+        (None, Some(path)) => Some(fs::read(path)?),
+        (None, None) => None,
+    };
+    let mut headers: HeaderMap = cli.headers.iter().cloned().collect();
+    for (name, value) in [(REFERER, &cli.referer), (ORIGIN, &cli.origin)] {
+        if let Some(value) = value {
+            headers.entry(name).or_insert_with(|| value.clone());
+        }
+    }
+    // 本文があれば JSON を既定の Content-Type とし、--header での指定を優先する
+    if body.is_some() && !headers.contains_key(CONTENT_TYPE) {
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    }
+    let method = cli.method.clone().unwrap_or(if body.is_some() {
+        Method::POST
+    } else {
+        Method::GET
+    });
+
+    let mut downloads = Downloads::new(&cli);
+    let options = DownloadOptions {
+        client: client.build()?,
+        method,
+        body,
+        headers,
+        use_server_timestamps: cli.use_server_timestamps || cli.timestamping,
+        timestamping: cli.timestamping,
+        tries: cli.tries,
+        retry_jitter: cli.retry_jitter,
+        checksum: cli.checksum_manifest_out.is_some()
+            || cli.audit_log.is_some()
+            || cli.expect_checksums.is_some(),
+        hash_thread: cli.hash_thread,
+        rate_limiter: Some(Arc::clone(&downloads.total_rate)),
+        pause: Some(Arc::clone(&downloads.pause)),
+        fail_empty: cli.fail_empty,
+        accept_status: cli.accept_status.clone(),
+        retry_all_methods: cli.retry_all_methods,
+        only_changed: cli.only_changed,
+        expect_content_type: cli.expect_content_type.clone(),
+        min_rate: cli.min_rate,
+        speed_window: cli.speed_window(),
+        gpg,
+        max_response_size: cli.max_response_size,
+        download_timeout: cli.download_timeout.map(Duration::from_secs_f64),
+        download_timeouts: cli
+            .download_timeouts
+            .iter()
+            .map(|(name, secs)| (name.clone(), Duration::from_secs_f64(*secs)))
+            .collect(),
+        expected_sizes: cli.expected_sizes.iter().cloned().collect(),
+        strict_size: cli.strict_size,
+        write_buffer: cli.write_buffer as usize,
+        buffer_size: cli.buffer_size.map(|size| size as usize),
+        decompress: cli.decompress,
+        milestones: cli.milestones,
+    };
+
+    // 指定したファイルのタスクだけを残し、.part があればその続きから取得する
+    if !cli.resume_only.is_empty() {
+        let selected = |task: &DownloadTask| {
+            cli.resume_only
+                .iter()
+                .any(|name| task.path.file_name() == Some(OsStr::new(name)))
+        };
+        if let Some(name) = cli.resume_only.iter().find(|name| {
+            !download_tasks
+                .iter()
+                .any(|task| task.path.file_name() == Some(OsStr::new(name.as_str())))
+        }) {
+            return Err(eyre!(
+                "--resume-only に指定したファイルをダウンロードする URL がありません: {}",
+                name
+            ));
+        }
+        download_tasks.retain(selected);
+        for task in &mut download_tasks {
+            if let Ok(metadata) = fs::metadata(part_path(&task.path)) {
+                task.resume_from = metadata.len();
+            }
+        }
+    }
+
+    // --state-db に記録があり、まだ終わっていない URL は .part の続きから取得する。完了済みのものは起動時に飛ばす
+    let state_db = cli.state_db.as_deref().map(StateDb::open).transpose()?;
+    if let Some(state_db) = &state_db {
+        resume_unfinished(state_db, &mut download_tasks)?;
+    }
+
+    // 保存先に残っている .part を対応する URL のタスクに割り当てて再開する
+    if cli.resume_all {
+        // --organize-by-host ではホストごとのディレクトリをそれぞれ調べる
+        let mut dirs: Vec<PathBuf> = download_tasks
+            .iter()
+            .filter_map(|task| task.path.parent().map(Path::to_path_buf))
+            .collect();
+        dirs.sort();
+        dirs.dedup();
+        for dir in dirs {
+            let scan_dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir.as_path()
+            };
+            for entry in fs::read_dir(scan_dir)? {
+                let entry = entry?;
+                let file_name = entry.file_name().to_string_lossy().into_owned();
+                let Some(name) = file_name.strip_suffix(".part") else {
+                    continue;
+                };
+                match download_tasks.iter_mut().find(|task| {
+                    task.path.parent() == Some(dir.as_path())
+                        && task.path.file_name() == Some(OsStr::new(name))
+                }) {
+                    Some(task) => task.resume_from = entry.metadata()?.len(),
+                    None => eprintln!("⚠ 対応する URL がないためスキップします: {}", file_name),
+                }
+            }
+        }
+    }
+
+    let (tx, rx) = mpsc::channel();
+    
+
+    let mut queue = TaskQueue {
+        options,
+        layout,
+        skip_existing: cli.skip_existing,
+        concat_urls: cli.concat.then(|| cli.urls.clone()),
+        zsync_manifest: cli.zsync.clone().or_else(|| cli.repair.clone()),
+        repair: cli.repair.is_some(),
+        concurrency: Arc::clone(&downloads.concurrency),
+        host_limits: Arc::clone(&downloads.host_limits),
+        request_rate: cli.rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        preflight: cli
+            .preflight
+            .then(|| Arc::new(ConcurrencyLimit::new(cli.jobs))),
+        tx: tx.clone(),
+        tasks: Vec::new(),
+        audit_log: cli.audit_log.as_deref().map(AuditLog::open).transpose()?,
+        state_db,
+        open: cli.open,
+        tar,
+        tar_next: 0,
+        expected_checksums,
+    };
+    // 全ダウンロードタスクを開始
+    for task in download_tasks {
+        queue.spawn(&mut downloads, task);
+    }
+
+    // --print-final-paths / --tar-stdout では標準出力をその出力だけにし、それ以外は標準エラー出力に出す
+    let to_stderr = cli.print_final_paths || cli.tar_stdout;
+    let say = |message: &str| {
+        if to_stderr {
+            eprintln!("{}", message);
+        } else {
+            println!("{}", message);
+        }
+    };
+
+    // パイプやリダイレクト先に UI を描いても読めないので、TTY でなければ静かに実行する
+    let quiet = cli.quiet
+        || cli.quiet_errors_only
+        || if to_stderr {
+            !io::stderr().is_terminal()
+        } else {
+            !io::stdout().is_terminal()
+        };
+    let started_at = Instant::now();
+    if let Some(secs) = cli.progress_heartbeat {
+        progress_heartbeat(tx.clone(), Duration::from_secs_f64(secs));
+    }
+    if quiet {
+        dump_progress_on_signal(tx);
+        run_quiet(&mut downloads, &mut queue, rx, cli.quiet_errors_only)?;
+        if !cli.quiet_errors_only {
+            say(&downloads.summary_line(started_at.elapsed()));
+        }
+    } else {
+        let viewport = Viewport::Inline(15);
+        let app_result = if to_stderr {
+            let mut terminal = init_stderr_terminal(viewport)?;
+            input_handling(tx);
+            let app_result = run(&mut terminal, &mut downloads, &mut queue, rx);
+            // ratatui::restore は標準出力に書き込むので、raw モードだけを戻す
+            crossterm::terminal::disable_raw_mode()?;
+            app_result
+        } else {
+            let mut terminal = ratatui::init_with_options(TerminalOptions { viewport });
+            input_handling(tx);
+            let app_result = run(&mut terminal, &mut downloads, &mut queue, rx);
+            // インストールの前にターミナルを閉じる
+            ratatui::restore();
+            app_result
+        };
+        app_result?;
+        say("すべてのダウンロードが完了しました。");
+    }
+    queue.finish_tar(&downloads.outcomes)?;
+    if cli.report {
+        for line in ordered_report(&queue.tasks, &downloads.outcomes) {
+            say(&line);
+        }
+    }
+    if let Some(path) = &cli.checksum_cache {
+        let mut cache = ChecksumCache::load(path)?;
+        fill_checksums(
+            &mut cache,
+            &queue.tasks,
+            &downloads.outcomes,
+            &mut downloads.checksums,
+        )?;
+        cache.save()?;
+    }
+    if queue.expected_checksums.is_some() {
+        let lines = queue.integrity_report(&downloads.checksums);
+        if !cli.quiet_errors_only {
+            for line in &lines {
+                say(line);
+            }
+        }
+        if let Some(report) = &cli.integrity_report {
+            fs::write(report, lines.join("\n") + "\n")?;
+        }
+    }
+
+    if let Some(webhook) = &cli.webhook {
+        let files: Vec<FileResult> = queue
+            .tasks
+            .iter()
+            .map(|task| FileResult {
+                url: &task.url,
+                path: &task.path,
+                outcome: downloads.outcomes.get(&task.id),
+                sha256: downloads.checksums.get(&task.id).map(String::as_str),
+            })
+            .collect();
+        let body = webhook::summary_json(
+            &files,
+            downloads.completed_bytes,
+            started_at.elapsed().as_secs_f64(),
+        );
+        // 通知が届かなくてもダウンロードの結果は変わらないので、警告だけ出して続ける
+        if let Err(e) = post_webhook(&queue.options.client, webhook, body).await {
+            eprintln!("⚠ webhook に送信できませんでした: {}", e);
+        }
+    }
+
+    // ダウンロードが完了したら、.deb ファイルをインストールする（--tar-stdout では手元に残らない）
+    let deb_files: Vec<&PathBuf> = queue
+        .tasks
+        .iter()
+        .filter(|_| !cli.tar_stdout)
+        .filter_map(|task| {
+            if task.path.extension().is_some_and(|ext| ext == "deb") {
+                Some(&task.path)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if !deb_files.is_empty() {
+        say(".deb ファイルのインストールを試みます...");
+        // 端末の有無で結果が変わらないよう、どちらでも dpkg には質問させない。
+        // sudo のパスワードだけは先に尋ねる（スピナーを回す場合は入力を上書きしないよう回し始める前に）
+        let authenticated = std::process::Command::new("sudo").arg("-v").status()?;
+        let status = if !authenticated.success() {
+            authenticated
+        } else if quiet {
+            let mut command = noninteractive_dpkg(&deb_files);
+            if to_stderr {
+                command.stdout(io::stderr());
+            }
+            command.status()?
+        } else {
+            // 端末ではスピナーを回して待ち、dpkg の出力は失敗したときだけ見せる
+            let names: Vec<String> = deb_files
+                .iter()
+                .map(|path| {
+                    path.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned()
+                })
+                .collect();
+            let label = format!("インストール中: {}", names.join(", "));
+            let command = noninteractive_dpkg(&deb_files);
+            let (status, output) = if to_stderr {
+                run_with_spinner(command, &label, &mut io::stderr())?
+            } else {
+                run_with_spinner(command, &label, &mut io::stdout())?
+            };
+            if !status.success() {
+                io::stderr().write_all(&output)?;
+            }
+            status
+        };
+
+        if status.success() {
+            say("インストールが正常に完了しました。");
+        } else {
+            eprintln!("インストールに失敗しました。終了コード: {:?}", status.code());
+        }
+    }
+
+    if let Some(manifest) = &cli.checksum_manifest_out {
+        write_checksum_manifest(manifest, &queue.tasks, &downloads.checksums)?;
+    }
+    if let (Some(path), Some(store)) = (&cli.cookie_jar, &cookie_store) {
+        save_cookie_jar(path, &store.lock().unwrap())?;
+    }
+    if cli.print_final_paths {
+        for path in final_paths(&queue.tasks, &downloads.outcomes) {
+            println!("{}", path.display());
+        }
+    }
+    exit_status(&downloads, cli.fail_on_skip)
+}
+
+/// 終了コードの判定。失敗したダウンロードが 1 つでもあれば、最後まで処理したうえで 0 以外で終わる
+fn exit_status(downloads: &Downloads, fail_on_skip: bool) -> Result<()> {
+    if !downloads.errors.is_empty() {
+        let names: Vec<&str> = downloads
+            .errors
+            .iter()
+            .map(|failed| failed.name.as_str())
+            .collect();
+        return Err(eyre!(
+            "{}件のダウンロードに失敗しました: {}",
+            names.len(),
+            names.join(", ")
+        ));
+    }
+    // CI では古い成果物が残っている合図になるので、スキップも失敗として扱えるようにする
+    if fail_on_skip && !downloads.skipped.is_empty() {
+        return Err(eyre!(
+            "{}件のダウンロードをスキップしました (--fail-on-skip): {}",
+            downloads.skipped.len(),
+            downloads.skipped.join(", ")
+        ));
+    }
+    Ok(())
+}
+
+/// .deb のインストールに使う `sudo dpkg -i`。スピナーを回す間は出力を受け取るので質問に答えられず、
+/// --quiet や TTY でない場合も答えられるとは限らないため、どちらでも尋ねずに進める。
+/// 設定ファイルの置き換えは尋ねずに既定の扱い（決まらなければ手元のものを残す）とし、sudo もパスワードを尋ねずに失敗させる
+fn noninteractive_dpkg(deb_files: &[&PathBuf]) -> std::process::Command {
+    let mut command = std::process::Command::new("sudo");
+    command
+        .args(["-n", "env", "DEBIAN_FRONTEND=noninteractive", "dpkg"])
+        .args(["--force-confdef", "--force-confold", "-i"])
+        .args(deb_files)
+        .stdin(std::process::Stdio::null());
+    command
+}
+
+/// スピナーの 1 コマの長さ
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `command` を実行し、終わるまで `out` の 1 行に「⠋ label」のスピナーを回す。
+/// 標準出力と標準エラー出力は受け取っておき、終了状態と一緒に返す（標準出力の内容が先）
+fn run_with_spinner(
+    mut command: std::process::Command,
+    label: &str,
+    out: &mut impl Write,
+) -> io::Result<(std::process::ExitStatus, Vec<u8>)> {
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()?;
+    // パイプが詰まって子プロセスが止まらないよう、別スレッドで読み続ける
+    let read_all = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    };
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+    let mut frame = 0;
+    let status = loop {
+        write!(
+            out,
+            "\r{} {}",
+            SPINNER_FRAMES[frame % SPINNER_FRAMES.len()],
+            label
+        )?;
+        out.flush()?;
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        frame += 1;
+        thread::sleep(SPINNER_INTERVAL);
+    };
+    // 回し終えたスピナーの行を消し、後のメッセージをその位置から書く
+    crossterm::execute!(
+        out,
+        crossterm::cursor::MoveToColumn(0),
+        crossterm::terminal::Clear(crossterm::terminal::ClearType::CurrentLine)
+    )?;
+    let mut output = stdout.join().unwrap_or_default();
+    output.extend(stderr.join().unwrap_or_default());
+    Ok((status, output))
+}
+
+/// --report: ファイルごとの結果を、終わった順ではなく URL を指定した順に番号を振って並べる
+fn ordered_report(tasks: &[DownloadTask], outcomes: &BTreeMap<DownloadId, Outcome>) -> Vec<String> {
+    let mut ordered: Vec<&DownloadTask> = tasks.iter().collect();
+    ordered.sort_by_key(|task| task.id);
+    let width = ordered.len().to_string().len();
+    ordered
+        .iter()
+        .enumerate()
+        .map(|(index, task)| {
+            let result = match outcomes.get(&task.id) {
+                Some(Outcome::Done) => "✓".to_string(),
+                Some(Outcome::Skipped(reason)) => format!("⏭ ({})", reason),
+                Some(Outcome::Failed(error)) => format!("✗ ({})", error),
+                None => "… (中断)".to_string(),
+            };
+            format!(
+                "[{:>width$}/{}] {} {}",
+                index + 1,
+                ordered.len(),
+                task.path.display(),
+                result
+            )
+        })
+        .collect()
+}
+
+/// `next` 以降で先頭から続けて結果の決まったタスクのうち、成功したものの保存先を返し、`next` を進める
+fn tar_ready<'a>(
+    tasks: &'a [DownloadTask],
+    outcomes: &BTreeMap<DownloadId, Outcome>,
+    next: &mut usize,
+) -> Vec<&'a Path> {
+    let mut ready = Vec::new();
+    while let Some(task) = tasks.get(*next) {
+        match outcomes.get(&task.id) {
+            None => break,
+            Some(Outcome::Done) => ready.push(task.path.as_path()),
+            Some(_) => {}
+        }
+        *next += 1;
+    }
+    ready
+}
+
+/// 保存して (--verify-gpg では検証まで) 終えたファイルの絶対パスをタスクの順に返す
+fn final_paths(tasks: &[DownloadTask], outcomes: &BTreeMap<DownloadId, Outcome>) -> Vec<PathBuf> {
+    tasks
+        .iter()
+        .filter(|task| matches!(outcomes.get(&task.id), Some(Outcome::Done)))
+        .map(|task| fs::canonicalize(&task.path).unwrap_or_else(|_| task.path.clone()))
+        .collect()
+}
+
+/// --print-final-paths 用に、標準エラー出力へ描く端末を用意する
+fn init_stderr_terminal(viewport: Viewport) -> io::Result<Terminal<StderrBackend>> {
+    crossterm::terminal::enable_raw_mode()?;
+    // ratatui::init と同じく、パニックしても端末を元に戻す
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let _ = crossterm::terminal::disable_raw_mode();
+        hook(info);
+    }));
+    Terminal::with_options(StderrBackend::new(), TerminalOptions { viewport })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::buffer::Buffer;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn downloads(args: &[&str]) -> Downloads {
+        let cli =
+            DownloadArgs::parse_from(std::iter::once("cli-tools").chain(args.iter().copied()));
+        Downloads::new(&cli)
+    }
+
+    fn add(downloads: &mut Downloads, id: DownloadId, name: &str, downloaded: u64, total: u64) {
+        downloads.in_progress.insert(
+            id,
+            DownloadInProgress {
+                id,
+                name: name.to_string(),
+                url: format!("https://example.com/{name}"),
+                started_at: Instant::now(),
+                downloaded,
+                total,
+                samples: VecDeque::new(),
+                speed_history: VecDeque::new(),
+                status: Status::Downloading,
+                verify_progress: None,
+            },
+        );
+    }
+
+    /// 全角文字の後ろに置かれる空のセルを飛ばして、画面を行ごとの文字列にする
+    fn lines(buffer: &Buffer) -> Vec<String> {
+        let area = buffer.area;
+        (area.top()..area.bottom())
+            .map(|y| {
+                let mut line = String::new();
+                let mut skip = 0;
+                for x in area.left()..area.right() {
+                    if skip > 0 {
+                        skip -= 1;
+                        continue;
+                    }
+                    let symbol = buffer[(x, y)].symbol();
+                    skip = text_width(symbol).saturating_sub(1);
+                    line.push_str(symbol);
+                }
+                line.trim_end().to_string()
+            })
+            .collect()
+    }
+
+    fn draw(downloads: &Downloads, width: u16, height: u16) -> Vec<String> {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| render(frame, downloads)).unwrap();
+        lines(terminal.backend().buffer())
+    }
+
+    #[test]
+    fn heartbeat_line_counts_bytes() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        add(&mut downloads, 1, "b.bin", 0, 1024 * 1024);
+        downloads.completed.push("c.bin".to_string());
+        downloads.completed_bytes = 1024;
+        assert_eq!(
+            heartbeat_line(&downloads),
+            "PROGRESS bytes=525312 pct=25.0 active=2"
+        );
+    }
+
+    #[test]
+    fn download_is_a_subcommand() {
+        let Command::Download(args) = Cli::parse_from([
+            "cli-tools",
+            "download",
+            "-j",
+            "2",
+            "https://example.com/a.bin",
+        ])
+        .into_command();
+        assert_eq!(args.jobs, 2);
+        assert_eq!(args.urls, ["https://example.com/a.bin"]);
+
+        // サブコマンドを省略すると download として扱う
+        let Command::Download(args) =
+            Cli::parse_from(["cli-tools", "-j", "2", "https://example.com/a.bin"]).into_command();
+        assert_eq!(args.jobs, 2);
+        assert_eq!(args.urls, ["https://example.com/a.bin"]);
+        let Command::Download(args) = Cli::parse_from(["cli-tools"]).into_command();
+        assert!(args.urls.is_empty());
+    }
+
+    #[test]
+    fn title_refreshes_only_on_change() {
+        let mut downloads = downloads(&["--set-title"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        let mut out = Vec::new();
+        let mut shown = String::new();
+        refresh_title(&mut out, &mut shown, &downloads).unwrap();
+        assert_eq!(shown, "cli-tools 50% (0/1)");
+        assert_eq!(out, b"\x1b]0;cli-tools 50% (0/1)\x07");
+
+        // 割合が変わらない受信では書き直さない
+        downloads.update(0, 513 * 1024, 1024 * 1024);
+        refresh_title(&mut out, &mut shown, &downloads).unwrap();
+        assert_eq!(out.len(), "\x1b]0;cli-tools 50% (0/1)\x07".len());
+        downloads.update(0, 1024 * 1024, 1024 * 1024);
+        refresh_title(&mut out, &mut shown, &downloads).unwrap();
+        assert!(out.ends_with(b"\x1b]0;cli-tools 100% (0/1)\x07"));
+    }
+
+    #[test]
+    fn render_empty() {
+        let lines = draw(&downloads(&[]), 80, 12);
+        assert_eq!(
+            lines[3],
+            " ダウンロード待機中...  同時実行数: 4 (+/-で変更, a: 追加, v: 表示, s: 設定)"
+        );
+        assert!(lines[1].contains("全体進捗 0/0"));
+        assert!(lines[6..].iter().all(|line| line.is_empty()));
+    }
+
+    #[test]
+    fn render_single() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        downloads.completed.push("b.bin".to_string());
+        let lines = draw(&downloads, 80, 12);
+        assert!(lines[1].contains("全体進捗 1/2"));
+        assert_eq!(
+            lines[3],
+            " ダウンロード中...  同時実行数: 4 (+/-で変更, a: 追加, v: 表示, s: 設定)"
+        );
+        assert_eq!(lines[4], " ⬇ 受信 1  ✓ 完了 1");
+        assert_eq!(
+            lines[6],
+            " [受信] 📦 a.bin (0.50/1.00MB, 0.00MB/s, 残り --)"
+        );
+        assert!(lines[7].contains("50.0%"));
+        assert!(lines[8].is_empty());
+    }
+
+    #[test]
+    fn speed_units_switch_to_mbps() {
+        assert_eq!(format_speed(1_048_576.0, SpeedUnit::Bytes), "1.00MB/s");
+        assert_eq!(format_speed(1_250_000.0, SpeedUnit::Bits), "10.00Mbps");
+        let mut downloads = downloads(&["--units", "bits"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        let lines = draw(&downloads, 80, 12);
+        assert_eq!(
+            lines[6],
+            " [受信] 📦 a.bin (0.50/1.00MB, 0.00Mbps, 残り --)"
+        );
+    }
+
+    #[test]
+    fn render_status_summary() {
+        let mut downloads = downloads(&[]);
+        for id in 0..3 {
+            add(&mut downloads, id, &format!("{id}.bin"), 0, 0);
+            downloads.set_status(id, Status::Queued);
+        }
+        add(&mut downloads, 3, "c.bin", 10, 100);
+        add(&mut downloads, 4, "d.bin", 10, 100);
+        downloads
+            .completed
+            .extend(["e.bin".to_string(), "f.bin".to_string()]);
+        add(&mut downloads, 5, "g.bin", 0, 0);
+        downloads.fail(5, "404");
+        let lines = draw(&downloads, 80, 12);
+        assert_eq!(
+            lines[4],
+            " ⏳ 待機 3  ⬇ 受信 2  ✓ 完了 2  ✗ 失敗 1  (e: 失敗の詳細, y: URL をコピー)"
+        );
+    }
+
+    #[test]
+    fn failed_urls_cycle_from_newest() {
+        let mut downloads = downloads(&[]);
+        assert_eq!(downloads.next_failed_url(), None);
+        add(&mut downloads, 0, "a.bin", 0, 0);
+        add(&mut downloads, 1, "b.bin", 0, 0);
+        downloads.fail(0, "404");
+        downloads.fail(1, "500");
+        let lines = draw(&downloads, 80, 12);
+        assert_eq!(lines[4], " ✗ 失敗 2  (e: 失敗の詳細, y: URL をコピー)");
+
+        let url = |name: &str| Some((name.to_string(), format!("https://example.com/{name}")));
+        assert_eq!(downloads.next_failed_url(), url("b.bin"));
+        assert_eq!(downloads.next_failed_url(), url("a.bin"));
+        assert_eq!(downloads.next_failed_url(), url("b.bin"));
+    }
+
+    #[test]
+    fn render_error_panel_scrolls_to_selection() {
+        let mut downloads = downloads(&[]);
+        for id in 0..3 {
+            add(&mut downloads, id, &format!("{id}.bin"), 0, 0);
+            downloads.fail(id, &format!("HTTP 50{id}"));
+        }
+        downloads.error_panel = Some(2);
+        let lines = draw(&downloads, 72, 14);
+        assert!(
+            lines[6].starts_with(" ┌失敗 3/3 (↑↓: 選択 / r: 再試行 / e: 閉じる)"),
+            "{lines:#?}"
+        );
+        // 5 行しか見えないので、選んだ最後の 1 件が下端に来るまでスクロールする
+        assert!(
+            lines[7].starts_with(" │   https://example.com/1.bin"),
+            "{lines:#?}"
+        );
+        assert!(lines[8].starts_with(" │   HTTP 501"));
+        assert!(lines[9].starts_with(" │ 2.bin"));
+        assert!(lines[10].starts_with(" │   https://example.com/2.bin"));
+
+        downloads.move_error_selection(false);
+        downloads.move_error_selection(false);
+        downloads.move_error_selection(false);
+        assert_eq!(downloads.error_panel, Some(0));
+        let lines = draw(&downloads, 72, 14);
+        assert!(lines[7].starts_with(" │ 0.bin"), "{lines:#?}");
+    }
+
+    #[test]
+    fn progress_snapshot_lists_downloads() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        add(&mut downloads, 1, "b.bin", 2048, 0);
+        downloads.set_status(1, Status::Connecting);
+        downloads.completed.push("c.bin".to_string());
+        assert_eq!(
+            downloads.progress_snapshot(),
+            vec![
+                "PROGRESS active=2 ok=1 failed=0 skipped=0".to_string(),
+                "  [受信] a.bin 512.00 KiB/1.00 MiB (50.0%) 0.00MB/s".to_string(),
+                "  [接続] b.bin 2.00 KiB 0.00MB/s".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn render_verify_progress() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 1024 * 1024, 1024 * 1024);
+        downloads.verify_update(0, 256 * 1024, 1024 * 1024);
+        let lines = draw(&downloads, 80, 12);
+        assert_eq!(lines[6], " [検証] ⠋ a.bin (検証 25.0%)");
+        assert!(
+            lines[7].contains("検証 256.00 KiB/1.00 MiB (25.0%)"),
+            "{}",
+            lines[7]
+        );
+
+        // 受信が再開したら元の表示に戻る
+        downloads.update(0, 1024 * 1024, 2048 * 1024);
+        let lines = draw(&downloads, 80, 12);
+        assert!(
+            lines[6].starts_with(" [受信] 📦 a.bin (1.00/2.00MB"),
+            "{}",
+            lines[6]
+        );
+    }
+
+    #[test]
+    fn exit_status_fails_on_errors_and_optionally_on_skips() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 0, 0);
+        add(&mut downloads, 1, "b.bin", 0, 0);
+        downloads.finish(0, None);
+        downloads.skip(1, SkipReason::Unchanged);
+        assert!(exit_status(&downloads, false).is_ok());
+        let error = exit_status(&downloads, true).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            "1件のダウンロードをスキップしました (--fail-on-skip): b.bin"
+        );
+
+        // 失敗は --fail-on-skip が無くても 0 以外で終わる
+        add(&mut downloads, 2, "c.bin", 0, 0);
+        downloads.fail(2, "接続できません");
+        for fail_on_skip in [false, true] {
+            let error = exit_status(&downloads, fail_on_skip)
+                .unwrap_err()
+                .to_string();
+            assert_eq!(error, "1件のダウンロードに失敗しました: c.bin");
+        }
+    }
+
+    #[test]
+    fn heartbeat_goes_above_the_ui_on_a_terminal() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        let mut terminal = Terminal::with_options(
+            TestBackend::new(80, 6),
+            TerminalOptions {
+                viewport: Viewport::Inline(4),
+            },
+        )
+        .unwrap();
+        print_heartbeat(&mut terminal, &downloads, true).unwrap();
+        let lines = lines(terminal.backend().buffer());
+        assert!(
+            lines.contains(&"PROGRESS bytes=524288 pct=50.0 active=1".to_string()),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn all_done_counts_without_scrollback() {
+        let mut downloads = downloads(&["--no-scrollback"]);
+        downloads
+            .completed
+            .extend(["a.bin".to_string(), "b.bin".to_string()]);
+        downloads.skipped.push("c.bin".to_string());
+        add(&mut downloads, 3, "d.bin", 0, 0);
+        downloads.fail(3, "接続できません");
+        let mut terminal = Terminal::with_options(
+            TestBackend::new(80, 6),
+            TerminalOptions {
+                viewport: Viewport::Inline(4),
+            },
+        )
+        .unwrap();
+        print_all_done(&mut terminal, &downloads).unwrap();
+        let lines = lines(terminal.backend().buffer());
+        assert!(
+            lines.contains(
+                &"🎉 すべてのダウンロードが完了しました！ (完了: 2件) (スキップ: 1件) (失敗: 1件)"
+                    .to_string()
+            ),
+            "{lines:?}"
+        );
+    }
+
+    #[test]
+    fn render_sparklines() {
+        let mut downloads = downloads(&["--sparklines"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        add(&mut downloads, 1, "b.bin", 0, 0);
+        downloads.set_status(1, Status::Connecting);
+        for _ in 0..SPARKLINE_LEN + 10 {
+            downloads.record_speeds();
+        }
+        assert_eq!(downloads.in_progress[&0].speed_history.len(), SPARKLINE_LEN);
+        // 受信していないダウンロードの速度は記録しない
+        assert!(downloads.in_progress[&1].speed_history.is_empty());
+
+        downloads.in_progress.get_mut(&0).unwrap().speed_history = VecDeque::from([1, 4, 8]);
+        let lines = draw(&downloads, 80, 12);
+        assert!(lines[7].contains("50.0%"));
+        assert_eq!(lines[8], " ▁▄█");
+    }
+
+    #[test]
+    fn render_overall_spinner_while_sizes_are_unknown() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 0);
+        add(&mut downloads, 1, "b.bin", 1024 * 1024, 0);
+        let lines = draw(&downloads, 72, 12);
+        assert_eq!(lines[1], " ⠋ 全体進捗 0/2 (1.50MB 受信)");
+        // 1 つでもサイズが分かれば、件数の比率のバーに戻る
+        add(&mut downloads, 2, "c.bin", 0, 1024);
+        let lines = draw(&downloads, 72, 12);
+        assert!(lines[1].starts_with(" 全体進捗 0/3 ───"), "{lines:#?}");
+        assert!(!lines[1].contains("受信"));
+    }
+
+    #[test]
+    fn render_paused_banner() {
+        let downloads = downloads(&[]);
+        downloads.pause.toggle();
+        let lines = draw(&downloads, 80, 12);
+        assert!(
+            lines[3]
+                .starts_with(" ⏸ 一時停止中  同時実行数: 4 (+/-で変更, a: 追加, v: 表示, s: 設定)"),
+            "{lines:#?}"
+        );
+    }
+
+    #[test]
+    fn render_settings_panel() {
+        let mut downloads = downloads(&["-j", "4", "--max-total-rate", "1M"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        downloads.settings = Some(Setting::Concurrency);
+        downloads.adjust_setting(true);
+        assert_eq!(downloads.concurrency.limit(), 5);
+
+        // 帯域は段階ごとに動き、最上段の上は無制限になる
+        downloads.settings = downloads.settings.map(|setting| setting.moved(true));
+        downloads.adjust_setting(false);
+        assert_eq!(downloads.total_rate.rate(), (512 * 1024) as f64);
+        downloads.total_rate.set_rate((128 << 20) as f64);
+        downloads.adjust_setting(true);
+        assert_eq!(downloads.total_rate.rate(), 0.0);
+
+        // ホストごとの上限は同時実行数より 1 つ少ないところまで。下げると無制限から入る
+        downloads.settings = downloads
+            .settings
+            .map(|setting| setting.moved(true).moved(true));
+        assert_eq!(downloads.settings, Some(Setting::PerHost));
+        downloads.adjust_setting(false);
+        assert_eq!(downloads.host_limits.limit(), 4);
+        downloads.adjust_setting(true);
+        assert_eq!(downloads.host_limits.limit(), 0);
+        downloads.adjust_setting(false);
+        downloads.adjust_setting(false);
+
+        let lines = draw(&downloads, 72, 12);
+        assert!(
+            lines[6].starts_with(" ┌設定 (↑↓: 選択 / ←→: 変更 / s: 閉じる)"),
+            "{lines:#?}"
+        );
+        assert!(lines[7].starts_with(" │ 同時実行数: ◀ 5 ▶ "));
+        assert!(lines[8].starts_with(" │ 合計帯域: ◀ 無制限 ▶ "));
+        assert!(lines[9].starts_with(" │ ホストごとの上限: ◀ 3 ▶ "));
+    }
+
+    #[test]
+    fn tar_stream_writes_done_files_in_order() {
+        let staging = output_dir("tar-stream");
+        let task = |id, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: staging.join(name),
+            resume_from: 0,
+        };
+        let tasks = [task(0, "a.bin"), task(1, "b.bin"), task(2, "c.bin")];
+        fs::write(staging.join("a.bin"), BODY).unwrap();
+        fs::write(staging.join("c.bin"), b"c").unwrap();
+        let mut tar = TarWriter::spawn(TarStream::new(Vec::new(), staging.clone()).unwrap());
+
+        // 前のタスクが終わるまで、先に終わった後ろのタスクは書き出さない
+        let mut outcomes = BTreeMap::from([(2, Outcome::Done)]);
+        let mut next = 0;
+        assert!(tar_ready(&tasks, &outcomes, &mut next).is_empty());
+        outcomes.insert(0, Outcome::Done);
+        outcomes.insert(1, Outcome::Failed("404".to_string()));
+        let ready = tar_ready(&tasks, &outcomes, &mut next);
+        assert_eq!(ready, vec![staging.join("a.bin"), staging.join("c.bin")]);
+        assert_eq!(next, 3);
+        for path in ready {
+            tar.append(path.to_path_buf()).unwrap();
+        }
+
+        let archive = tar.finish().unwrap();
+        assert!(!staging.exists());
+        let mut archive = tar::Archive::new(archive.as_slice());
+        let entries: Vec<(String, Vec<u8>)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut data = Vec::new();
+                entry.read_to_end(&mut data).unwrap();
+                (name, data)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("a.bin".to_string(), BODY.to_vec()),
+                ("c.bin".to_string(), b"c".to_vec())
+            ]
+        );
+
+        // 途中で抜けても作業ディレクトリは残さない
+        let tar = TarWriter::spawn(TarStream::new(Vec::new(), staging.clone()).unwrap());
+        fs::write(staging.join("a.bin"), BODY).unwrap();
+        drop(tar);
+        assert!(!staging.exists());
+    }
+
+    #[test]
+    fn final_paths_lists_done_files() {
+        let dir = output_dir("final-paths");
+        let task = |id, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: dir.join(name),
+            resume_from: 0,
+        };
+        let tasks = [
+            task(0, "a.bin"),
+            task(1, "b.bin"),
+            task(2, "c.bin"),
+            task(3, "d.bin"),
+        ];
+        fs::write(dir.join("a.bin"), BODY).unwrap();
+        fs::write(dir.join("d.bin"), BODY).unwrap();
+        let outcomes = BTreeMap::from([
+            (0, Outcome::Done),
+            (1, Outcome::Failed("404".to_string())),
+            (2, Outcome::Skipped("変更なし".to_string())),
+            (3, Outcome::Done),
+        ]);
+        let dir = fs::canonicalize(&dir).unwrap();
+        assert_eq!(
+            final_paths(&tasks, &outcomes),
+            vec![dir.join("a.bin"), dir.join("d.bin")]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn install_spinner_captures_output() {
+        let mut command = std::process::Command::new("sh");
+        command.args(["-c", "echo installed; echo warning >&2; sleep 0.3; exit 3"]);
+        let mut screen = Vec::new();
+        let (status, output) =
+            run_with_spinner(command, "インストール中: a.deb", &mut screen).unwrap();
+        assert_eq!(status.code(), Some(3));
+        assert_eq!(output, b"installed\nwarning\n");
+        let screen = String::from_utf8(screen).unwrap();
+        assert!(
+            screen.starts_with("\r⠋ インストール中: a.deb\r⠙ インストール中: a.deb"),
+            "{screen:?}"
+        );
+        assert!(!screen.contains("installed"));
+
+        let deb = PathBuf::from("a.deb");
+        let command = noninteractive_dpkg(&[&deb]);
+        assert_eq!(command.get_program(), "sudo");
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            [
+                "-n",
+                "env",
+                "DEBIAN_FRONTEND=noninteractive",
+                "dpkg",
+                "--force-confdef",
+                "--force-confold",
+                "-i",
+                "a.deb"
+            ]
+        );
+    }
+
+    #[test]
+    fn report_follows_input_order() {
+        let task = |id, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: PathBuf::from(name),
+            resume_from: 0,
+        };
+        // UI から追加したタスクは後ろに並ぶが、番号は id の順に振る
+        let tasks = [
+            task(1, "b.bin"),
+            task(0, "a.bin"),
+            task(2, "c.bin"),
+            task(3, "d.bin"),
+        ];
+        let outcomes = BTreeMap::from([
+            (2, Outcome::Done),
+            (0, Outcome::Failed("404".to_string())),
+            (1, Outcome::Skipped("変更なし".to_string())),
+        ]);
+        assert_eq!(
+            ordered_report(&tasks, &outcomes),
+            vec![
+                "[1/4] a.bin ✗ (404)",
+                "[2/4] b.bin ⏭ (変更なし)",
+                "[3/4] c.bin ✓",
+                "[4/4] d.bin … (中断)",
+            ]
+        );
+    }
+
+    #[test]
+    fn integrity_report_compares_hashes() {
+        let dir = output_dir("integrity-report");
+        let good = hex(&Sha256::digest(BODY));
+        let bad = "0".repeat(64);
+        let sums = dir.join("SHA256SUMS");
+        fs::write(
+            &sums,
+            format!(
+                "{}  a.bin\n{} *sub/b.bin\n\n{}  d.bin\n",
+                good.to_uppercase(),
+                bad,
+                good
+            ),
+        )
+        .unwrap();
+        let expected = load_expected_checksums(&sums).unwrap();
+        assert_eq!(expected["a.bin"], good);
+        assert_eq!(expected["b.bin"], bad);
+
+        let task = |id, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: PathBuf::from(name),
+            resume_from: 0,
+        };
+        let tasks = [
+            task(0, "a.bin"),
+            task(1, "b.bin"),
+            task(2, "c.bin"),
+            task(3, "d.bin"),
+        ];
+        let checksums = BTreeMap::from([(0, good.clone()), (1, good.clone()), (2, good.clone())]);
+        let lines = integrity_report(&tasks, &checksums, &expected);
+        assert_eq!(
+            lines[0],
+            "整合性の確認: 一致 1 / 不一致 1 / 期待値なし 1 / 未取得 1"
+        );
+        assert_eq!(
+            lines[1],
+            format!("✓ 一致  a.bin  期待値 {good}  実際 {good}")
+        );
+        assert_eq!(
+            lines[2],
+            format!("✗ 不一致  b.bin  期待値 {bad}  実際 {good}")
+        );
+        assert_eq!(
+            lines[3],
+            format!("- 期待値なし  c.bin  期待値 -  実際 {good}")
+        );
+        assert_eq!(lines[4], format!("… 未取得  d.bin  期待値 {good}  実際 -"));
+
+        fs::write(&sums, "not a checksum line\n").unwrap();
+        assert!(load_expected_checksums(&sums).is_err());
+        // ディレクトリだけが違う同名のファイルは、どちらの期待値か決められない
+        fs::write(&sums, format!("{good}  a.bin\n{good}  sub/a.bin\n")).unwrap();
+        let error = load_expected_checksums(&sums).unwrap_err().to_string();
+        assert!(
+            error.starts_with("ファイル名 a.bin の行が複数あります"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn state_db_survives_reopen() {
+        let dir = output_dir("state-db");
+        let url = "https://example.com/a.bin";
+        {
+            let state_db = StateDb::open(&dir.join("state")).unwrap();
+            assert_eq!(state_db.get(url).unwrap(), None);
+            state_db.put(url, StoredStatus::Downloading).unwrap();
+        }
+        // 書いた分は開き直しても残り、同じ URL の記録は上書きされる
+        let state_db = StateDb::open(&dir.join("state")).unwrap();
+        assert_eq!(state_db.get(url).unwrap(), Some(StoredStatus::Downloading));
+        state_db.put(url, StoredStatus::Done).unwrap();
+        assert_eq!(state_db.get(url).unwrap(), Some(StoredStatus::Done));
+    }
+
+    #[test]
+    fn state_db_skips_done_and_resumes_the_rest() {
+        let dir = output_dir("state-db-resume");
+        let state_db = StateDb::open(&dir.join("state")).unwrap();
+        let task = |id: DownloadId, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: dir.join(name),
+            resume_from: 0,
+        };
+        let mut tasks = vec![
+            task(0, "done.bin"),
+            task(1, "removed.bin"),
+            task(2, "partial.bin"),
+            task(3, "failed.bin"),
+        ];
+        state_db.put(&tasks[0].url, StoredStatus::Done).unwrap();
+        state_db.put(&tasks[1].url, StoredStatus::Done).unwrap();
+        state_db
+            .put(&tasks[2].url, StoredStatus::Downloading)
+            .unwrap();
+        state_db.put(&tasks[3].url, StoredStatus::Failed).unwrap();
+        fs::write(dir.join("done.bin"), BODY).unwrap();
+        fs::write(part_path(&dir.join("done.bin")), &BODY[..5]).unwrap();
+        fs::write(part_path(&dir.join("partial.bin")), &BODY[..10]).unwrap();
+        fs::write(part_path(&dir.join("failed.bin")), &BODY[..20]).unwrap();
+
+        // 完了済みでも保存したファイルが消えていれば取り直す
+        assert!(finished_before(&state_db, &tasks[0]));
+        assert!(!finished_before(&state_db, &tasks[1]));
+        assert!(!finished_before(&state_db, &tasks[2]));
+
+        resume_unfinished(&state_db, &mut tasks).unwrap();
+        let resume_from: Vec<u64> = tasks.iter().map(|task| task.resume_from).collect();
+        assert_eq!(resume_from, [0, 0, 10, 20]);
+    }
+
+    #[test]
+    fn checksum_cache_skips_unchanged_files() {
+        let dir = output_dir("checksum-cache");
+        let task = |id, name: &str| DownloadTask {
+            id,
+            url: format!("https://example.com/{name}"),
+            path: dir.join(name),
+            resume_from: 0,
+        };
+        let tasks = [task(0, "a.bin"), task(1, "b.bin")];
+        fs::write(dir.join("a.bin"), BODY).unwrap();
+        fs::write(dir.join("b.bin"), BODY).unwrap();
+        let cache_path = dir.join("cache");
+        let sha256 = hex(&Sha256::digest(BODY));
+
+        // 1 回目: 取得した a.bin のハッシュを覚え、スキップした b.bin は読んで計算する
+        let mut cache = ChecksumCache::load(&cache_path).unwrap();
+        let outcomes = BTreeMap::from([
+            (0, Outcome::Done),
+            (1, Outcome::Skipped("既に存在します".to_string())),
+        ]);
+        let mut checksums = BTreeMap::from([(0, "cached".to_string())]);
+        fill_checksums(&mut cache, &tasks, &outcomes, &mut checksums).unwrap();
+        assert_eq!(checksums[&1], sha256);
+        cache.save().unwrap();
+
+        // 2 回目: 変わっていない a.bin は読み直さずに覚えた値を使い、サイズの変わった b.bin は計算し直す
+        fs::write(dir.join("b.bin"), &BODY[..10]).unwrap();
+        let mut cache = ChecksumCache::load(&cache_path).unwrap();
+        let outcomes = BTreeMap::from([
+            (0, Outcome::Skipped("既に存在します".to_string())),
+            (1, Outcome::Skipped("既に存在します".to_string())),
+        ]);
+        let mut checksums = BTreeMap::new();
+        fill_checksums(&mut cache, &tasks, &outcomes, &mut checksums).unwrap();
+        assert_eq!(checksums[&0], "cached");
+        assert_eq!(checksums[&1], hex(&Sha256::digest(&BODY[..10])));
+    }
+
+    #[test]
+    fn render_byte_label_right_aligned() {
+        let mut downloads = downloads(&["--gauge-label", "bytes"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        let lines = draw(&downloads, 72, 12);
+        assert!(lines[7].ends_with("512.00 KiB/1.00 MiB"));
+    }
+
+    #[test]
+    fn render_narrow_drops_stats_and_truncates_name() {
+        let mut downloads = downloads(&[]);
+        add(
+            &mut downloads,
+            0,
+            "a_very_long_file_name.tar.gz",
+            512 * 1024,
+            1024 * 1024,
+        );
+        add(
+            &mut downloads,
+            1,
+            "日本語のファイル名.zip",
+            512 * 1024,
+            1024 * 1024,
+        );
+        let lines = draw(&downloads, 30, 14);
+        assert_eq!(lines[6], " [受信] 📦 a_very_lo… (50.0%)");
+        assert_eq!(lines[9], " [受信] 📦 日本語の… (50.0%)");
+    }
+
+    #[test]
+    fn render_long_names_keep_gauges() {
+        let long = "a_filename_taken_from_a_very_long_url_with_many_segments-v1.2.3.tar.gz";
+        let mut downloads = downloads(&["--max-name-length", "24"]);
+        downloads.queue(0, long, "https://example.com/", 0);
+        downloads.update(0, 512 * 1024, 1024 * 1024);
+        assert_eq!(downloads.in_progress[&0].name, "a_filename_t….2.3.tar.gz");
+
+        let lines = draw(&downloads, 80, 12);
+        assert!(
+            lines[6].starts_with(" [受信] 📦 a_filename_t….2.3.tar.gz (0.50/1.00MB"),
+            "{}",
+            lines[6]
+        );
+        assert!(lines[7].contains("50.0%"));
+
+        // 制限がなくても、狭い幅ではどの表示形式でも名前を縮めて進捗を残す
+        let mut uncapped = self::downloads(&[]);
+        add(&mut uncapped, 0, long, 512 * 1024, 1024 * 1024);
+        for _ in 0..3 {
+            let lines = draw(&uncapped, 40, 12);
+            assert!(
+                lines[6..].iter().any(|line| line.contains("50.0%")),
+                "{lines:?}"
+            );
+            assert!(lines.iter().all(|line| text_width(line) <= 40));
+            uncapped.view_mode = uncapped.view_mode.next();
+        }
+    }
+
+    #[test]
+    fn truncate_name_keeps_graphemes() {
+        assert_eq!(truncate_name("short.txt", 20), "short.txt");
+        assert_eq!(truncate_name("漢字のなまえ.txt", 7), "漢字の…");
+        // 結合文字 (が = か + U+3099) と ZWJ で繋いだ絵文字は途中で切らない
+        assert_eq!(truncate_name("か\u{3099}な.txt", 4), "か\u{3099}…");
+        assert_eq!(
+            truncate_name("👨\u{200d}👩\u{200d}👧.png", 3),
+            "👨\u{200d}👩\u{200d}👧…"
+        );
+    }
+
+    #[test]
+    fn render_show_url_truncates_middle() {
+        let mut downloads = downloads(&["--show-url"]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        let lines = draw(&downloads, 80, 12);
+        assert_eq!(
+            lines[6],
+            " [受信] 📦 a.bin (0.50/1.00MB, 0.00MB/s, 残り --) https://example.com/a.bin"
+        );
+        let lines = draw(&downloads, 72, 12);
+        assert_eq!(
+            lines[6],
+            " [受信] 📦 a.bin (0.50/1.00MB, 0.00MB/s, 残り --) https://ex….com/a.bin"
+        );
+        assert_eq!(
+            truncate_middle("https://例え.jp/ファイル", 12),
+            "https:…イル"
+        );
+    }
+
+    #[test]
+    fn render_status_tags() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 0, 0);
+        downloads.set_status(0, Status::Queued);
+        let lines = draw(&downloads, 72, 12);
+        assert_eq!(lines[6], " [待機] 📦 a.bin");
+
+        downloads.start(0);
+        let lines = draw(&downloads, 72, 12);
+        assert_eq!(lines[6], " [接続] ⠋ a.bin");
+
+        downloads.update(0, 1024, 2048);
+        let lines = draw(&downloads, 72, 12);
+        assert!(lines[6].starts_with(" [受信] 📦 a.bin (0.00/0.00MB"));
+
+        // 一時停止中は受信中のものを [停止] と表示する
+        downloads.pause.toggle();
+        let lines = draw(&downloads, 72, 12);
+        assert!(
+            lines[6].starts_with(" [停止] 📦 a.bin (0.00/0.00MB"),
+            "{lines:#?}"
+        );
+        assert!(downloads.progress_snapshot()[1].starts_with("  [停止] a.bin"));
+    }
+
+    #[test]
+    fn speed_color_follows_peak() {
+        assert_eq!(speed_color(0.0, 0.0), Color::White);
+        assert_eq!(speed_color(90.0, 100.0), Color::Green);
+        assert_eq!(speed_color(30.0, 100.0), Color::Yellow);
+        assert_eq!(speed_color(10.0, 100.0), Color::Red);
+    }
+
+    #[test]
+    fn render_table_view() {
+        let mut downloads = downloads(&[]);
+        add(&mut downloads, 0, "a.bin", 512 * 1024, 1024 * 1024);
+        add(&mut downloads, 1, "b.bin", 2 * 1024 * 1024, 0);
+        downloads.view_mode = downloads.view_mode.next().next();
+        assert!(downloads.view_mode == ViewMode::Table);
+        let lines = draw(&downloads, 72, 12);
+        assert_eq!(
+            lines[6],
+            " 状態   ファイル                          進捗      速度        残り"
+        );
+        assert_eq!(
+            lines[7],
+            " [受信] a.bin                             50.0%     0.00MB/s    --"
+        );
+        assert_eq!(
+            lines[8],
+            " [受信] b.bin                             2.00MB    0.00MB/s    --"
+        );
+    }
+
+    #[test]
+    fn webhook_summary_json() {
+        let failed = Outcome::Failed("HTTP 404 \"Not Found\"".to_string());
+        let files = [
+            FileResult {
+                url: "https://a/1",
+                path: Path::new("1.bin"),
+                outcome: Some(&Outcome::Done),
+                sha256: Some("ab"),
+            },
+            FileResult {
+                url: "https://a/2",
+                path: Path::new("2.bin"),
+                outcome: Some(&failed),
+                sha256: None,
+            },
+            FileResult {
+                url: "https://a/3",
+                path: Path::new("3.bin"),
+                outcome: None,
+                sha256: None,
+            },
+        ];
+        assert_eq!(
+            webhook::summary_json(&files, 10, 1.25),
+            concat!(
+                r#"{"ok":1,"failed":1,"skipped":0,"bytes":10,"secs":1.2,"files":["#,
+                r#"{"url":"https://a/1","path":"1.bin","status":"done","sha256":"ab"},"#,
+                r#"{"url":"https://a/2","path":"2.bin","status":"failed","reason":"HTTP 404 \"Not Found\""},"#,
+                r#"{"url":"https://a/3","path":"3.bin","status":"incomplete"}]}"#,
+            )
+        );
+    }
+
+    #[test]
+    fn render_clips_many() {
+        let mut downloads = downloads(&[]);
+        for id in 0..5 {
+            add(&mut downloads, id, &format!("file{id}.bin"), 0, 100);
+        }
+        // ダウンロード詳細の領域は 7 行なので、3 行ずつ使う表示では 2 件だけ入る
+        let lines = draw(&downloads, 72, 14);
+        let shown: Vec<&String> = lines.iter().filter(|line| line.contains("📦")).collect();
+        assert_eq!(shown.len(), 2);
+        assert!(shown[0].contains("file0.bin"));
+        assert!(shown[1].contains("file1.bin"));
+    }
+
+    #[test]
+    fn render_oneline_clips_many() {
+        let mut downloads = downloads(&["--oneline"]);
+        for id in 0..10 {
+            add(&mut downloads, id, &format!("file{id}.bin"), 0, 100);
+        }
+        let lines = draw(&downloads, 72, 14);
+        let shown = lines.iter().filter(|line| line.contains(".bin")).count();
+        assert_eq!(shown, 7);
+    }
+
+    #[test]
+    fn render_results_after_done() {
+        let mut downloads = downloads(&["--wait-on-done"]);
+        add(&mut downloads, 0, "a.bin", 0, 0);
+        assert!(!downloads.finished());
+        downloads.in_progress.clear();
+        downloads.completed.push("b.bin".to_string());
+        downloads.skipped.push("c.bin".to_string());
+        add(&mut downloads, 3, "d.bin", 0, 0);
+        downloads.fail(3, "HTTP 404");
+        assert!(downloads.finished());
+        let lines = draw(&downloads, 72, 12);
+        assert!(
+            lines[6].starts_with(" ┌結果 (↑↓: スクロール / q: 終了)"),
+            "{lines:#?}"
+        );
+        assert!(lines[7].starts_with(" │ ✓ b.bin"));
+        assert!(lines[8].starts_with(" │ ⏭ c.bin"));
+        assert!(lines[9].starts_with(" │ ✗ d.bin - HTTP 404"));
+
+        // --expect-checksums の照合結果も後ろに並べる
+        downloads.integrity = vec![
+            "整合性の確認: 一致 1 / 不一致 0 / 期待値なし 0 / 未取得 0".to_string(),
+            "✓ 一致  b.bin".to_string(),
+        ];
+        let lines = draw(&downloads, 72, 15);
+        assert_eq!(
+            lines[10],
+            " │                                                                    │"
+        );
+        assert!(
+            lines[11].starts_with(" │ 整合性の確認: 一致 1 / 不一致 0"),
+            "{lines:#?}"
+        );
+        assert!(lines[12].starts_with(" │ ✓ 一致  b.bin"), "{lines:#?}");
+    }
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn options() -> DownloadOptions {
+        DownloadOptions {
+            client: reqwest::Client::new(),
+            method: Method::GET,
+            body: None,
+            headers: HeaderMap::new(),
+            use_server_timestamps: false,
+            timestamping: false,
+            tries: 1,
+            retry_jitter: 0.0,
+            checksum: false,
+            hash_thread: false,
+            rate_limiter: None,
+            pause: None,
+            fail_empty: false,
+            accept_status: Vec::new(),
+            retry_all_methods: false,
+            only_changed: false,
+            expect_content_type: None,
+            min_rate: None,
+            speed_window: Duration::from_secs(5),
+            gpg: None,
+            max_response_size: None,
+            download_timeout: None,
+            download_timeouts: BTreeMap::new(),
+            expected_sizes: BTreeMap::new(),
+            strict_size: false,
+            write_buffer: 64 * 1024,
+            buffer_size: None,
+            decompress: false,
+            milestones: false,
+        }
+    }
+
+    /// テストごとに空の保存先ディレクトリを用意する
+    fn output_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cli-tools-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn download(
+        url: String,
+        path: &Path,
+        resume_from: u64,
+    ) -> (Result<(), String>, Vec<Event>) {
+        download_with(options(), url, path, resume_from).await
+    }
+
+    async fn download_with(
+        options: DownloadOptions,
+        url: String,
+        path: &Path,
+        resume_from: u64,
+    ) -> (Result<(), String>, Vec<Event>) {
+        let task = DownloadTask {
+            id: 0,
+            url,
+            path: path.to_path_buf(),
+            resume_from,
+        };
+        let (tx, rx) = mpsc::channel();
+        let result = download_with_progress(task, options, tx)
+            .await
+            .map_err(|e| e.to_string());
+        (result, rx.try_iter().collect())
+    }
+
+    /// 進捗通知が単調に増えて `written` に達し、最後に完了が通知されたか
+    fn assert_done(events: &[Event], written: u64, total: u64) {
+        let (last, updates) = events.split_last().expect("イベントがありません");
+        assert!(matches!(last, Event::DownloadDone(0, None)), "{last:?}");
+        let mut previous = 0;
+        for event in updates {
+            let Event::DownloadUpdate(0, downloaded, update_total) = event else {
+                panic!("想定外のイベント: {event:?}");
+            };
+            assert!(*downloaded >= previous);
+            assert_eq!(*update_total, total);
+            previous = *downloaded;
+        }
+        assert_eq!(previous, written);
+    }
+
+    #[test]
+    fn retry_after_is_capped() {
+        let headers = |value: &str| {
+            HeaderMap::from_iter([(RETRY_AFTER, HeaderValue::from_str(value).unwrap())])
+        };
+        assert_eq!(retry_after(&headers("120")), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after(&headers("86400")), Some(MAX_RETRY_AFTER));
+        let far =
+            httpdate::fmt_http_date(std::time::SystemTime::now() + Duration::from_secs(86400));
+        assert_eq!(retry_after(&headers(&far)), Some(MAX_RETRY_AFTER));
+        assert_eq!(
+            retry_after(&headers("Thu, 01 Jan 1970 00:00:00 GMT")),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon")), None);
+    }
+
+    #[tokio::test]
+    async fn download_with_content_length() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/file.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("content-length").join("file.bin");
+
+        let (result, events) = download(format!("{}/file.bin", server.uri()), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert!(!part_path(&dest).exists());
+        let len = BODY.len() as u64;
+        assert_done(&events, len, len);
+    }
+
+    /// 1 回だけ `head` と BODY を返して接続を閉じるサーバー
+    async fn serve_raw(head: &'static [u8]) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request).await;
+            stream.write_all(head).await.unwrap();
+            stream.write_all(BODY).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn download_without_content_length() {
+        // wiremock は Content-Length を必ず付けるので、接続を閉じて本文の終わりを示す
+        let addr = serve_raw(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").await;
+        let dest = output_dir("no-content-length").join("file.bin");
+
+        let (result, events) = download(format!("http://{addr}/file.bin"), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert_done(&events, BODY.len() as u64, 0);
+    }
+
+    #[tokio::test]
+    async fn download_truncated_body_is_error() {
+        let addr =
+            serve_raw(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n").await;
+        let dest = output_dir("truncated").join("file.bin");
+
+        let (result, _) = download(format!("http://{addr}/file.bin"), &dest, 0).await;
+        assert_eq!(
+            result,
+            Err(format!("truncated: got {} of 100 bytes", BODY.len()))
+        );
+        assert!(!dest.exists());
+        // 再試行で続きから取得できるよう .part は残す
+        assert_eq!(fs::read(part_path(&dest)).unwrap(), BODY);
+    }
+
+    #[test]
+    fn check_writable_explains_failures() {
+        let dir = output_dir("writable");
+        assert_eq!(check_writable(&dir.join("file.bin")), Ok(()));
+        assert!(!part_path(&dir.join("file.bin")).exists());
+        assert_eq!(
+            check_writable(&dir),
+            Err(format!("cannot write to {}: is a directory", dir.display()))
+        );
+        let missing = dir.join("missing").join("file.bin");
+        assert_eq!(
+            check_writable(&missing),
+            Err(format!(
+                "cannot write to {}: parent directory does not exist",
+                missing.display()
+            ))
+        );
+        fs::write(dir.join("plain"), b"").unwrap();
+        let under_file = dir.join("plain").join("file.bin");
+        assert_eq!(
+            check_writable(&under_file),
+            Err(format!(
+                "cannot write to {}: parent is not a directory",
+                under_file.display()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn download_splits_chunks_by_buffer_size() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("buffer-size").join("file.bin");
+        let mut options = options();
+        options.buffer_size = Some(10);
+        options.checksum = true;
+        options.hash_thread = true;
+
+        let (result, events) =
+            download_with(options, format!("{}/file.bin", server.uri()), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        // 10 バイトずつハッシュ用スレッドに送っても、ハッシュは本文全体のものになる
+        let expected = zsync::hash_hex(BODY);
+        assert!(
+            matches!(events.last(), Some(Event::DownloadDone(0, Some(sha256))) if *sha256 == expected)
+        );
+        // 進捗は 10 バイトごとではなく、受信したチャンクごとに通知される
+        let updates: Vec<u64> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::DownloadUpdate(_, downloaded, _) => Some(*downloaded),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(updates, vec![36]);
+    }
+
+    #[tokio::test]
+    async fn download_stops_at_max_response_size() {
+        // 長さを申告せずに上限を超える本文を送るサーバー
+        let addr = serve_raw(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n").await;
+        let dest = output_dir("max-response-size").join("file.bin");
+        let mut options = options();
+        options.max_response_size = Some(10);
+
+        let (result, _) = download_with(options, format!("http://{addr}/file.bin"), &dest, 0).await;
+        assert_eq!(
+            result,
+            Err("レスポンスが上限 (10 バイト) を超えたため中断しました".to_string())
+        );
+        assert!(!dest.exists());
+        assert!(fs::metadata(part_path(&dest)).unwrap().len() <= 10);
+    }
+
+    #[tokio::test]
+    async fn pause_holds_downloads_until_resumed() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("pause").join("file.bin");
+        let pause = Arc::new(PauseSwitch::new());
+        assert!(pause.toggle());
+        let mut options = options();
+        options.pause = Some(Arc::clone(&pause));
+
+        let download = download_with(options, format!("{}/file.bin", server.uri()), &dest, 0);
+        tokio::pin!(download);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), &mut download)
+                .await
+                .is_err()
+        );
+        assert!(!pause.toggle());
+        let (result, events) = download.await;
+        assert_eq!(result, Ok(()));
+        assert_done(&events, BODY.len() as u64, BODY.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn preflight_reads_size_from_head() {
+        let server = MockServer::start().await;
+        Mock::given(method("HEAD"))
+            .and(path("/file.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        Mock::given(method("HEAD"))
+            .and(path("/no-head.bin"))
+            .respond_with(ResponseTemplate::new(405))
+            .mount(&server)
+            .await;
+        let options = options();
+        assert_eq!(
+            preflight_size(&format!("{}/file.bin", server.uri()), &options).await,
+            Some(BODY.len() as u64)
+        );
+        assert_eq!(
+            preflight_size(&format!("{}/no-head.bin", server.uri()), &options).await,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn download_accepts_only_listed_statuses() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/gone.bin"))
+            .respond_with(ResponseTemplate::new(404).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/file.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dir = output_dir("accept-status");
+        let mut options = options();
+        options.accept_status = vec![StatusCode::NOT_FOUND];
+
+        let (result, _) = download_with(
+            options.clone(),
+            format!("{}/gone.bin", server.uri()),
+            &dir.join("gone.bin"),
+            0,
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(dir.join("gone.bin")).unwrap(), BODY);
+        // 一覧に無ければ 200 でも失敗にする
+        let (result, _) = download_with(
+            options,
+            format!("{}/file.bin", server.uri()),
+            &dir.join("file.bin"),
+            0,
+        )
+        .await;
+        assert_eq!(
+            result,
+            Err("HTTP 200 OK (--accept-status に含まれていません)".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn resume_treats_416_as_complete() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("range", "bytes=36-"))
+            .respond_with(ResponseTemplate::new(416).insert_header("content-range", "bytes */36"))
+            .mount(&server)
+            .await;
+        let dir = output_dir("resume-416");
+        let dest = dir.join("file.bin");
+        fs::write(part_path(&dest), BODY).unwrap();
+        let mut options = options();
+        options.checksum = true;
+
+        let (result, events) =
+            download_with(options, format!("{}/file.bin", server.uri()), &dest, 36).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert!(!part_path(&dest).exists());
+        let sha256 = hex(&Sha256::digest(BODY));
+        assert!(
+            matches!(events.last(), Some(Event::DownloadDone(0, Some(done))) if *done == sha256),
+            "{events:?}"
+        );
+
+        // 416 でも全体の大きさが合わなければ完了とはみなさない
+        assert!(range_complete(&HeaderMap::new(), 36));
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_RANGE, HeaderValue::from_static("bytes */40"));
+        assert!(!range_complete(&headers, 36));
+    }
+
+    #[test]
+    fn retry_jitter_shortens_delay() {
+        let delay = Duration::from_secs(8);
+        assert_eq!(jittered(delay, 0.0, 0.9), delay);
+        assert_eq!(jittered(delay, 0.5, 0.0), delay);
+        assert_eq!(jittered(delay, 0.5, 0.5), Duration::from_secs(6));
+        assert_eq!(jittered(delay, 1.0, 0.75), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn timestamping_skips_when_local_is_current() {
+        let server = MockServer::start().await;
+        // If-Modified-Since を無視して 200 を返すサーバーでも Last-Modified で判断する
+        Mock::given(method("GET"))
+            .and(path("/old.bin"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("last-modified", "Sun, 06 Nov 1994 08:49:37 GMT")
+                    .set_body_bytes(BODY),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/new.bin"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("last-modified", "Fri, 01 Jan 2100 00:00:00 GMT")
+                    .set_body_bytes(BODY),
+            )
+            .mount(&server)
+            .await;
+        let dir = output_dir("timestamping");
+        fs::write(dir.join("old.bin"), b"local").unwrap();
+        fs::write(dir.join("new.bin"), b"local").unwrap();
+        let mut options = options();
+        options.timestamping = true;
+        options.use_server_timestamps = true;
+
+        let (result, events) = download_with(
+            options.clone(),
+            format!("{}/old.bin", server.uri()),
+            &dir.join("old.bin"),
+            0,
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        assert!(
+            matches!(
+                &events[..],
+                [Event::DownloadSkipped(0, SkipReason::UpToDate)]
+            ),
+            "{events:?}"
+        );
+        assert_eq!(fs::read(dir.join("old.bin")).unwrap(), b"local");
+
+        let (result, _) = download_with(
+            options,
+            format!("{}/new.bin", server.uri()),
+            &dir.join("new.bin"),
+            0,
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(dir.join("new.bin")).unwrap(), BODY);
+
+        let mut downloads = downloads(&["-N"]);
+        add(&mut downloads, 0, "old.bin", 0, 0);
+        add(&mut downloads, 1, "other.bin", 0, 0);
+        downloads.skip(0, SkipReason::UpToDate);
+        downloads.skip(1, SkipReason::Unchanged);
+        assert_eq!((downloads.skipped.len(), downloads.up_to_date), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn download_reports_milestones() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("milestones").join("file.bin");
+        let mut options = options();
+        options.milestones = true;
+
+        let (result, events) =
+            download_with(options, format!("{}/file.bin", server.uri()), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        let percents: Vec<u32> = events
+            .iter()
+            .filter_map(|event| match event {
+                Event::DownloadMilestone(0, percent) => Some(*percent),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(percents, (1..=10).map(|n| n * 10).collect::<Vec<_>>());
+
+        // 再開した場合は越えた区切りを飛ばす。サイズが分からなければ追わない
+        let mut milestones = Milestones::new(18, 36).unwrap();
+        assert_eq!(milestones.reached(20), Vec::<u32>::new());
+        assert_eq!(milestones.reached(25), vec![60]);
+        assert_eq!(milestones.reached(36), vec![70, 80, 90, 100]);
+        assert!(Milestones::new(0, 0).is_none());
+    }
+
+    #[tokio::test]
+    async fn download_checks_expected_size() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("expect-size").join("file.bin");
+        let mut options = options();
+        options.expected_sizes = BTreeMap::from([("file.bin".to_string(), 40)]);
+
+        // 既定では警告だけ出してそのまま取得する
+        let (result, events) = download_with(
+            options.clone(),
+            format!("{}/file.bin", server.uri()),
+            &dest,
+            0,
+        )
+        .await;
+        assert_eq!(result, Ok(()));
+        let mismatch = "サイズが想定と違います (Content-Length: 36 バイト, 期待値: 40 バイト)";
+        assert!(
+            matches!(&events[0], Event::DownloadWarning(0, warning) if warning == mismatch),
+            "{events:?}"
+        );
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+
+        fs::remove_file(&dest).unwrap();
+        options.strict_size = true;
+        let (result, _) =
+            download_with(options, format!("{}/file.bin", server.uri()), &dest, 0).await;
+        assert_eq!(result, Err(mismatch.to_string()));
+        assert!(!dest.exists());
+        assert!(!part_path(&dest).exists());
+    }
+
+    #[tokio::test]
+    async fn download_timeout_fails_without_retry() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(BODY)
+                    .set_delay(Duration::from_secs(5)),
+            )
+            .mount(&server)
+            .await;
+        let dest = output_dir("download-timeout").join("file.bin");
+        let mut options = options();
+        options.tries = 3;
+        options.download_timeout = Some(Duration::from_millis(100));
+
+        let task = DownloadTask {
+            id: 0,
+            url: format!("{}/file.bin", server.uri()),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let (tx, rx) = mpsc::channel();
+        let result = download_with_tries(task, options, tx).await;
+        assert_eq!(result, Err("download timed out".to_string()));
+        assert!(rx
+            .try_iter()
+            .all(|event| !matches!(event, Event::DownloadRetry(..))));
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn download_timeout_per_file_and_paused_time() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_bytes(BODY)
+                    .set_delay(Duration::from_millis(300)),
+            )
+            .mount(&server)
+            .await;
+        let dir = output_dir("download-timeout-for");
+        let task = |name: &str| DownloadTask {
+            id: 0,
+            url: format!("{}/{name}", server.uri()),
+            path: dir.join(name),
+            resume_from: 0,
+        };
+        let mut options = options();
+        options.download_timeout = Some(Duration::from_millis(200));
+        options.download_timeouts =
+            BTreeMap::from([("slow.bin".to_string(), Duration::from_secs(5))]);
+
+        // ファイル名ごとの指定が --download-timeout より優先される
+        let (tx, _rx) = mpsc::channel();
+        download_with_timeout(task("slow.bin"), options.clone(), tx.clone())
+            .await
+            .unwrap();
+        let result = download_with_timeout(task("fast.bin"), options.clone(), tx.clone()).await;
+        assert_eq!(result.unwrap_err().to_string(), "download timed out");
+
+        // 一時停止している間は制限時間に数えない
+        let pause = Arc::new(PauseSwitch::new());
+        pause.toggle();
+        options.pause = Some(Arc::clone(&pause));
+        let resume = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            pause.toggle();
+        });
+        download_with_timeout(task("paused.bin"), options, tx)
+            .await
+            .unwrap();
+        resume.await.unwrap();
+        assert_eq!(fs::read(dir.join("paused.bin")).unwrap(), BODY);
+    }
+
+    #[tokio::test]
+    async fn download_reconnects_when_too_slow() {
+        // 1 回目は本文の途中で止まり、2 回目は Range の続きを返すサーバー
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let (mut stalled, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let n = stalled.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stalled
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 36\r\n\r\n")
+                .await
+                .unwrap();
+            stalled.write_all(&BODY[..10]).await.unwrap();
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let n = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 26\r\nContent-Range: bytes 10-35/36\r\n\r\n")
+                .await
+                .unwrap();
+            stream.write_all(&BODY[10..]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            requests
+        });
+        let dest = output_dir("slow-restart").join("file.bin");
+        let mut options = options();
+        options.min_rate = Some(1);
+        options.speed_window = Duration::from_millis(200);
+
+        let task = DownloadTask {
+            id: 0,
+            url: format!("http://{addr}/file.bin"),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let (tx, rx) = mpsc::channel();
+        assert_eq!(download_with_tries(task, options, tx).await, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let requests = server.await.unwrap();
+        assert!(
+            requests[1].to_lowercase().contains("range: bytes=10-"),
+            "{requests:?}"
+        );
+        // 試行回数は進めず、再接続の回数として知らせる
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(events
+            .iter()
+            .all(|event| !matches!(event, Event::DownloadRetry(..))));
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::DownloadReconnect(0, 1, MAX_SLOW_RESTARTS, _))));
+    }
+
+    #[tokio::test]
+    async fn download_resumes_after_connection_reset() {
+        // 1 回目は本文の途中で RST を送って切り、2 回目は Range の続きを返すサーバー
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 36\r\n\r\n")
+                .await
+                .unwrap();
+            stream.write_all(&BODY[..10]).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            stream.set_zero_linger().unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let n = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 26\r\nContent-Range: bytes 10-35/36\r\n\r\n")
+                .await
+                .unwrap();
+            stream.write_all(&BODY[10..]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            requests
+        });
+        let dest = output_dir("connection-reset").join("file.bin");
+
+        // 試行回数が 1 回でも、リセットは数えずに続きから取り直す
+        let task = DownloadTask {
+            id: 0,
+            url: format!("http://{addr}/file.bin"),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let (tx, rx) = mpsc::channel();
+        assert_eq!(download_with_tries(task, options(), tx).await, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let requests = server.await.unwrap();
+        assert!(
+            requests[1].to_lowercase().contains("range: bytes=10-"),
+            "{requests:?}"
+        );
+        // 試行回数は進めず、リセットによる再接続の回数として知らせる
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(events
+            .iter()
+            .all(|event| !matches!(event, Event::DownloadRetry(..))));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            Event::DownloadReconnect(0, 1, MAX_RESET_RESUMES, error) if error == "受信中に接続が切れました (connection reset, 10 バイト受信済み)"
+        )));
+    }
+
+    #[tokio::test]
+    async fn download_reconnects_after_long_pause() {
+        // 一時停止している間に本文の途中で接続を閉じ、2 回目は Range の続きを返すサーバー
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let n = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 36\r\n\r\n")
+                .await
+                .unwrap();
+            stream.write_all(&BODY[..10]).await.unwrap();
+            stream.shutdown().await.unwrap();
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let n = stream.read(&mut request).await.unwrap();
+            requests.push(String::from_utf8_lossy(&request[..n]).into_owned());
+            stream
+                .write_all(b"HTTP/1.1 206 Partial Content\r\nContent-Length: 26\r\nContent-Range: bytes 10-35/36\r\n\r\n")
+                .await
+                .unwrap();
+            stream.write_all(&BODY[10..]).await.unwrap();
+            stream.shutdown().await.unwrap();
+            requests
+        });
+        let dest = output_dir("pause-reconnect").join("file.bin");
+        let pause = Arc::new(PauseSwitch::new());
+        pause.toggle();
+        let mut options = options();
+        options.pause = Some(Arc::clone(&pause));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            pause.toggle();
+        });
+
+        let task = DownloadTask {
+            id: 0,
+            url: format!("http://{addr}/file.bin"),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let (tx, rx) = mpsc::channel();
+        assert_eq!(download_with_tries(task, options, tx).await, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let requests = server.await.unwrap();
+        assert!(
+            requests[1].to_lowercase().contains("range: bytes=10-"),
+            "{requests:?}"
+        );
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::DownloadReconnect(0, 1, MAX_RESET_RESUMES, _))));
+    }
+
+    #[tokio::test]
+    async fn download_decompresses_gzip() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(BODY).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/file.txt.gz"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(compressed.clone()))
+            .mount(&server)
+            .await;
+        let url = format!("{}/file.txt.gz", server.uri());
+        assert_eq!(saved_name(&url, true), "file.txt");
+        assert_eq!(
+            saved_name(&format!("{}/file.zip", server.uri()), true),
+            "file.zip"
+        );
+        let dest = output_dir("decompress").join("file.txt");
+        let mut options = options();
+        options.decompress = true;
+        options.checksum = true;
+
+        let (result, events) = download_with(options, url, &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        // 進捗は受信した圧縮後のバイト数で、ハッシュは保存した内容のもの
+        let len = compressed.len() as u64;
+        assert!(
+            matches!(events[events.len() - 2], Event::DownloadUpdate(0, written, total) if written == len && total == len)
+        );
+        let expected = hex(&Sha256::digest(BODY));
+        assert!(
+            matches!(events.last(), Some(Event::DownloadDone(0, Some(sha256))) if *sha256 == expected)
+        );
+    }
+
+    #[tokio::test]
+    async fn download_limits_decompressed_size() {
+        // 1 MiB のゼロは数 KiB に圧縮されるので、受信したバイト数だけでは上限に届かない
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        encoder.write_all(&vec![0u8; 1024 * 1024]).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/zeros.gz"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(compressed.clone()))
+            .mount(&server)
+            .await;
+        let dest = output_dir("decompress-limit").join("zeros");
+        let mut options = options();
+        options.decompress = true;
+        options.tries = 3;
+        options.max_response_size = Some(64 * 1024);
+        assert!((compressed.len() as u64) < 64 * 1024);
+
+        let task = DownloadTask {
+            id: 0,
+            url: format!("{}/zeros.gz", server.uri()),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let (tx, rx) = mpsc::channel();
+        let result = download_with_tries(task, options, tx).await;
+        assert_eq!(
+            result,
+            Err("レスポンスが上限 (65536 バイト) を超えたため中断しました".to_string())
+        );
+        assert!(rx
+            .try_iter()
+            .all(|event| !matches!(event, Event::DownloadRetry(..))));
+        assert!(!dest.exists());
+    }
+
+    #[tokio::test]
+    async fn download_server_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .mount(&server)
+            .await;
+        let dest = output_dir("server-error").join("file.bin");
+
+        let (result, events) = download(format!("{}/file.bin", server.uri()), &dest, 0).await;
+        assert!(result.unwrap_err().contains("500"));
+        assert!(events.is_empty(), "{events:?}");
+        assert!(!dest.exists());
+        assert!(!part_path(&dest).exists());
+    }
+
+    #[tokio::test]
+    async fn download_follows_redirect() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/old.bin"))
+            .respond_with(ResponseTemplate::new(302).insert_header("Location", "/new.bin"))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/new.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        let dest = output_dir("redirect").join("old.bin");
+
+        let (result, events) = download(format!("{}/old.bin", server.uri()), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let len = BODY.len() as u64;
+        assert_done(&events, len, len);
+    }
+
+    #[tokio::test]
+    async fn download_resumes_part_file() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("Range", "bytes=10-"))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(&BODY[10..]))
+            .mount(&server)
+            .await;
+        let dest = output_dir("resume").join("file.bin");
+        fs::write(part_path(&dest), &BODY[..10]).unwrap();
+
+        let (result, events) = download(format!("{}/file.bin", server.uri()), &dest, 10).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let len = BODY.len() as u64;
+        assert_done(&events, len, len);
+    }
+
+    #[tokio::test]
+    async fn download_resume_sends_if_range() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(header("Range", "bytes=10-"))
+            .and(header("If-Range", "\"v1\""))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(&BODY[10..]))
+            .mount(&server)
+            .await;
+        // ETag が一致しなければサーバーは本文全体を返す
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("ETag", "\"v2\"")
+                    .set_body_bytes(b"changed"),
+            )
+            .mount(&server)
+            .await;
+        let dir = output_dir("if-range");
+        let len = BODY.len() as u64;
+
+        // 取り始めたときと同じファイルなら続きを追記する
+        let unchanged = dir.join("unchanged.bin");
+        fs::write(part_path(&unchanged), &BODY[..10]).unwrap();
+        Sidecar {
+            etag: Some("\"v1\"".to_string()),
+            last_modified: None,
+        }
+        .save(&part_path(&unchanged))
+        .unwrap();
+        let (result, events) =
+            download(format!("{}/unchanged.bin", server.uri()), &unchanged, 10).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&unchanged).unwrap(), BODY);
+        assert_done(&events, len, len);
+        assert!(!sidecar::sidecar_path(&part_path(&unchanged)).exists());
+
+        // 変わっていれば .part を捨てて新しい本文だけを保存する
+        let changed = dir.join("changed.bin");
+        fs::write(part_path(&changed), &BODY[..10]).unwrap();
+        Sidecar {
+            etag: Some("\"v0\"".to_string()),
+            last_modified: None,
+        }
+        .save(&part_path(&changed))
+        .unwrap();
+        let (result, events) =
+            download(format!("{}/changed.bin", server.uri()), &changed, 10).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&changed).unwrap(), b"changed");
+        assert_done(&events, 7, 7);
+        assert!(!sidecar::sidecar_path(&part_path(&changed)).exists());
+    }
+
+    #[tokio::test]
+    async fn repair_refetches_broken_blocks() {
+        let server = MockServer::start().await;
+        let mut manifest = format!("length: {}\nblocksize: 8\n", BODY.len());
+        for block in BODY.chunks(8) {
+            manifest.push_str(&format!("{}\n", zsync::hash_hex(block)));
+        }
+        Mock::given(method("GET"))
+            .and(path("/file.bin.manifest"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(manifest))
+            .mount(&server)
+            .await;
+        for (range, body) in [("bytes=8-15", &BODY[8..16]), ("bytes=32-35", &BODY[32..])] {
+            Mock::given(method("GET"))
+                .and(path("/file.bin"))
+                .and(header("Range", range))
+                .respond_with(ResponseTemplate::new(206).set_body_bytes(body))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        // ブロック 1 と末尾のブロック 4 が壊れたファイル
+        let dest = output_dir("repair").join("file.bin");
+        let mut broken = BODY.to_vec();
+        broken[10] = b'X';
+        broken[33] = b'X';
+        fs::write(&dest, &broken).unwrap();
+        let task = || DownloadTask {
+            id: 0,
+            url: format!("{}/file.bin", server.uri()),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+        let manifest_url = format!("{}/file.bin.manifest", server.uri());
+
+        let (tx, rx) = mpsc::channel();
+        download_zsync(task(), manifest_url.clone(), true, options(), tx)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(events.iter().any(|event| matches!(event, Event::DownloadRepaired(0, repaired) if repaired == "ブロック 1, 4")));
+
+        // 直したあとは何も取得しない
+        let (tx, rx) = mpsc::channel();
+        download_zsync(task(), manifest_url, true, options(), tx)
+            .await
+            .unwrap();
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(matches!(
+            events.as_slice(),
+            [Event::DownloadSkipped(0, SkipReason::NothingToRepair)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn repair_copies_large_files_in_chunks() {
+        // 読み込みのバッファ (64 KiB) より大きい区間を手元のファイルから写す
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let block_size = 64 * 1024;
+        let server = MockServer::start().await;
+        let mut manifest = format!("length: {}\nblocksize: {}\n", data.len(), block_size);
+        for block in data.chunks(block_size) {
+            manifest.push_str(&format!("{}\n", zsync::hash_hex(block)));
+        }
+        Mock::given(method("GET"))
+            .and(path("/large.bin.manifest"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(manifest))
+            .mount(&server)
+            .await;
+        let broken_block = 2 * block_size..3 * block_size;
+        Mock::given(method("GET"))
+            .and(path("/large.bin"))
+            .and(header(
+                "Range",
+                format!("bytes={}-{}", broken_block.start, broken_block.end - 1).as_str(),
+            ))
+            .respond_with(ResponseTemplate::new(206).set_body_bytes(&data[broken_block.clone()]))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let dest = output_dir("repair-large").join("large.bin");
+        let mut broken = data.clone();
+        broken[broken_block.start + 100] ^= 0xff;
+        fs::write(&dest, &broken).unwrap();
+        let task = DownloadTask {
+            id: 0,
+            url: format!("{}/large.bin", server.uri()),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        download_zsync(
+            task,
+            format!("{}/large.bin.manifest", server.uri()),
+            true,
+            options(),
+            tx,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), data);
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(events.iter().any(|event| matches!(event, Event::DownloadRepaired(0, repaired) if repaired == "ブロック 2")));
+        // 先頭の 2 ブロック (128 KiB) は 1 回で読まず、バッファごとに進捗を知らせる
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::DownloadUpdate(0, 65536, _))));
+    }
+
+    #[test]
+    fn hash_thread_accepts_any_hashing_option() {
+        for option in [
+            "--checksum-manifest-out",
+            "--audit-log",
+            "--expect-checksums",
+        ] {
+            let args = [
+                "cli-tools",
+                option,
+                "x",
+                "--hash-thread",
+                "https://example.com/a",
+            ];
+            assert!(DownloadArgs::try_parse_from(args).is_ok(), "{option}");
+        }
+    }
+
+    #[tokio::test]
+    async fn concat_fails_when_a_part_is_short() {
+        // HEAD では b.bin を 16 バイトと答えるのに、GET では 10 バイトで接続を閉じるサーバー
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                let n = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..n]).into_owned();
+                let (head, body): (String, &[u8]) = match &request[..10] {
+                    "HEAD /a.bi" => ("Content-Length: 20\r\n".to_string(), b""),
+                    "HEAD /b.bi" => ("Content-Length: 16\r\n".to_string(), b""),
+                    "GET /a.bin" => ("Content-Length: 20\r\n".to_string(), &BODY[..20]),
+                    _ => (String::new(), &BODY[20..30]),
+                };
+                let response = format!("HTTP/1.1 200 OK\r\n{head}Connection: close\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.write_all(body).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        let dest = output_dir("concat-short").join("all.bin");
+        let urls = vec![
+            format!("http://{addr}/a.bin"),
+            format!("http://{addr}/b.bin"),
+        ];
+        let task = DownloadTask {
+            id: 0,
+            url: urls[0].clone(),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let error = download_concat(task, urls.clone(), options(), tx)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(error, format!("truncated: {}: got 10 of 16 bytes", urls[1]));
+        // 壊れたファイルを公開せず、組み立てかけの .part も残さない
+        assert!(!dest.exists());
+        assert!(!part_path(&dest).exists());
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert!(!events
+            .iter()
+            .any(|event| matches!(event, Event::DownloadDone(..))));
+    }
+
+    #[tokio::test]
+    async fn concat_continues_when_head_is_rejected() {
+        // HEAD には応答しない（wiremock は 404 を返す）ので、全体のサイズは不明になる
+        let server = MockServer::start().await;
+        for (name, body) in [("/a.bin", &BODY[..20]), ("/b.bin", &BODY[20..])] {
+            Mock::given(method("GET"))
+                .and(path(name))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(body))
+                .mount(&server)
+                .await;
+        }
+        let dest = output_dir("concat").join("all.bin");
+        let urls = vec![
+            format!("{}/a.bin", server.uri()),
+            format!("{}/b.bin", server.uri()),
+        ];
+        let task = DownloadTask {
+            id: 0,
+            url: urls[0].clone(),
+            path: dest.clone(),
+            resume_from: 0,
+        };
+
+        let (tx, rx) = mpsc::channel();
+        download_concat(task, urls, options(), tx).await.unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        let events: Vec<Event> = rx.try_iter().collect();
+        assert_done(&events, BODY.len() as u64, 0);
+
+        // 1 回の GET で連結するので、試行回数やメソッドの指定とは組み合わせられない
+        assert!(DownloadArgs::try_parse_from([
+            "cli-tools",
+            "--concat",
+            "-o",
+            "all.bin",
+            "https://example.com/a"
+        ])
+        .is_ok());
+        for option in [["--tries", "3"], ["-X", "PUT"], ["--data", "{}"]] {
+            let args = [
+                "cli-tools",
+                "--concat",
+                "-o",
+                "all.bin",
+                option[0],
+                option[1],
+                "https://example.com/a",
+            ];
+            assert!(DownloadArgs::try_parse_from(args).is_err(), "{option:?}");
+        }
+    }
+
+    /// 鍵を生成して公開鍵を `keyring` に書き出し、`data` の分離署名を返す
+    fn sign(keyring: &Path, data: &[u8]) -> (String, Vec<u8>) {
+        use sequoia_openpgp::cert::CertBuilder;
+        use sequoia_openpgp::policy::StandardPolicy;
+        use sequoia_openpgp::serialize::stream::{Message, Signer};
+        use sequoia_openpgp::serialize::Serialize;
+
+        let (cert, _) = CertBuilder::general_purpose(Some("test"))
+            .generate()
+            .unwrap();
+        cert.serialize(&mut File::create(keyring).unwrap()).unwrap();
+        let policy = StandardPolicy::new();
+        let key = cert
+            .keys()
+            .with_policy(&policy, None)
+            .secret()
+            .for_signing()
+            .next()
+            .unwrap();
+        let key_id = key.key().keyid().to_hex();
+        let keypair = key.key().clone().into_keypair().unwrap();
+
+        let mut signature = Vec::new();
+        let mut signer = Signer::new(Message::new(&mut signature), keypair)
+            .unwrap()
+            .detached()
+            .build()
+            .unwrap();
+        signer.write_all(data).unwrap();
+        signer.finalize().unwrap();
+        (key_id, signature)
+    }
+
+    #[tokio::test]
+    async fn download_verifies_gpg_signature() {
+        let dir = output_dir("gpg");
+        let keyring = dir.join("keyring.pgp");
+        let (key_id, signature) = sign(&keyring, BODY);
+        let other = dir.join("other.sig");
+        fs::write(&other, sign(&dir.join("other.pgp"), BODY).1).unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/file.bin"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(BODY))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/file.bin.sig"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(signature))
+            .mount(&server)
+            .await;
+        let url = format!("{}/file.bin", server.uri());
+        let dest = dir.join("file.bin");
+        let verifying = |signature: &str| {
+            let mut options = options();
+            options.gpg = Some(Arc::new(
+                Verifier::new(signature.to_string(), &keyring).unwrap(),
+            ));
+            options
+        };
+
+        let (result, events) = download_with(verifying("{url}.sig"), url.clone(), &dest, 0).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(fs::read(&dest).unwrap(), BODY);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, Event::DownloadVerified(0, signer) if *signer == key_id)));
+        let len = BODY.len() as u64;
+        assert!(events.iter().any(|event| matches!(event, Event::DownloadVerifyUpdate(0, read, total) if *read == len && *total == len)));
+        fs::remove_file(&dest).unwrap();
+
+        // 鍵束にない鍵の署名
+        let (result, _) =
+            download_with(verifying(&other.to_string_lossy()), url.clone(), &dest, 0).await;
+        assert!(result.unwrap_err().contains("署名の検証に失敗しました"));
+        assert!(!dest.exists());
+        assert!(!part_path(&dest).exists());
+
+        // 署名が見つからない
+        let (result, _) = download_with(verifying("{url}.asc"), url, &dest, 0).await;
+        assert!(result.unwrap_err().contains("署名を取得できません"));
+        assert!(!dest.exists());
+        assert!(!part_path(&dest).exists());
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// 全ダウンロードで共有するトークンバケット方式の流量制限
+///
+/// 取得した量だけトークンを消費し、足りない分は借り越して
+/// その返済にかかる時間だけ待つ。バーストは 1 秒分まで許す。
+/// --max-total-rate ではバイト数、--rate ではリクエストの数を単位にする。
+/// 上限は実行中に変更でき、0 は無制限として扱う。
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: f64) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                per_sec,
+                tokens: per_sec,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.bucket.lock().unwrap().per_sec
+    }
+
+    /// 上限を変える。溜まっていたトークンは新しい上限の 1 秒分までに切り詰める
+    pub fn set_rate(&self, per_sec: f64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.per_sec = per_sec;
+        bucket.tokens = bucket.tokens.min(per_sec);
+        bucket.last_refill = Instant::now();
+    }
+
+    /// `amount` 分を確保できるまで待つ
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.per_sec <= 0.0 {
+                return;
+            }
+            let now = Instant::now();
+            let refill = now.duration_since(bucket.last_refill).as_secs_f64() * bucket.per_sec;
+            bucket.tokens = (bucket.tokens + refill).min(bucket.per_sec);
+            bucket.last_refill = now;
+            bucket.tokens -= amount as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / bucket.per_sec)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex,
+};
+
+use tokio::sync::Notify;
+
+/// 実行中に変更できる同時ダウンロード数の上限
+///
+/// 固定サイズのセマフォと違い、上限を下げても実行中のダウンロードは止めず、
+/// 新しく開始する分だけを絞る。
+pub struct ConcurrencyLimit {
+    limit: AtomicUsize,
+    active: Mutex<usize>,
+    notify: Notify,
+}
+
+/// 枠を確保している間だけ保持する。ドロップで枠を返す
+pub struct Permit<'a> {
+    owner: &'a ConcurrencyLimit,
+}
+
+/// ホストごとの同時ダウンロード数の上限 (--max-per-host)
+///
+/// ホストを初めて見たときにそのホスト用の ConcurrencyLimit を作る。
+/// 0 は無制限で、実行中に変更するとすべてのホストに効く。
+pub struct HostLimits {
+    limit: AtomicUsize,
+    hosts: Mutex<HashMap<String, Arc<ConcurrencyLimit>>>,
+}
+
+/// HostLimits から確保した枠。ドロップで枠を返す
+pub struct HostPermit {
+    owner: Arc<ConcurrencyLimit>,
+}
+
+/// Space で切り替える全体の一時停止
+///
+/// 止めている間は受信中のダウンロードが次のチャンクを読まずに待ち、
+/// 新しいダウンロードも始めない。長く止めているとサーバーが接続を閉じることがあるが、
+/// そのときは再開後に .part の続きから接続し直す。
+pub struct PauseSwitch {
+    paused: AtomicBool,
+    notify: Notify,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit.max(1)),
+            active: Mutex::new(0),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit.max(1), Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    /// 空きができるまで待ってから枠を確保する
+    pub async fn acquire(&self) -> Permit<'_> {
+        self.reserve().await;
+        Permit { owner: self }
+    }
+
+    /// 空きができるまで待ってから実行中の数を 1 つ増やす。終わったら release で戻す
+    async fn reserve(&self) {
+        loop {
+            // 確認より先に待機登録しておき、その間の解放を取りこぼさない
+            let notified = self.notify.notified();
+            {
+                let mut active = self.active.lock().unwrap();
+                if *active < self.limit() {
+                    *active += 1;
+                    return;
+                }
+            }
+            notified.await;
+        }
+    }
+
+    fn release(&self) {
+        *self.active.lock().unwrap() -= 1;
+        self.notify.notify_waiters();
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.owner.release();
+    }
+}
+
+impl HostLimits {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit: AtomicUsize::new(limit),
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.store(limit, Ordering::Relaxed);
+        for host in self.hosts.lock().unwrap().values() {
+            host.set_limit(effective(limit));
+        }
+    }
+
+    /// `host` の空きができるまで待ってから枠を確保する
+    pub async fn acquire(&self, host: &str) -> HostPermit {
+        let owner = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let limit = self.limit();
+            Arc::clone(
+                hosts
+                    .entry(host.to_string())
+                    .or_insert_with(|| Arc::new(ConcurrencyLimit::new(effective(limit)))),
+            )
+        };
+        owner.reserve().await;
+        HostPermit { owner }
+    }
+}
+
+/// 0 (無制限) を ConcurrencyLimit の上限に直す
+fn effective(limit: usize) -> usize {
+    if limit == 0 {
+        usize::MAX
+    } else {
+        limit
+    }
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        self.owner.release();
+    }
+}
+
+impl PauseSwitch {
+    pub fn new() -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// 止めていれば再開し、動いていれば止める。切り替え後に止まっていれば true
+    pub fn toggle(&self) -> bool {
+        let paused = !self.paused.fetch_xor(true, Ordering::Relaxed);
+        if !paused {
+            self.notify.notify_waiters();
+        }
+        paused
+    }
+
+    /// 止まっていれば再開されるまで待つ。止まっていたら true
+    pub async fn wait(&self) -> bool {
+        let mut waited = false;
+        loop {
+            let notified = self.notify.notified();
+            if !self.is_paused() {
+                return waited;
+            }
+            waited = true;
+            notified.await;
+        }
+    }
+}
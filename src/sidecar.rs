@@ -0,0 +1,82 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+
+/// ダウンロードしたファイルの横に置く `<name>.meta` に記録する検証子
+///
+/// 次回の実行で条件付きリクエスト (If-None-Match / If-Modified-Since) に使う。
+/// 書きかけの `<name>.part` にも `<name>.part.meta` を置き、再開時の If-Range に使う。
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Sidecar {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Sidecar {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let get = |name| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        Self {
+            etag: get(ETAG),
+            last_modified: get(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// `path` に対応するサイドカーを読む。無い場合や中身が空の場合は None
+    pub fn load(path: &Path) -> Option<Self> {
+        let text = fs::read_to_string(sidecar_path(path)).ok()?;
+        let mut sidecar = Self::default();
+        for line in text.lines() {
+            match line.split_once(": ") {
+                Some(("etag", value)) => sidecar.etag = Some(value.to_string()),
+                Some(("last-modified", value)) => sidecar.last_modified = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        (!sidecar.is_empty()).then_some(sidecar)
+    }
+
+    /// Range で再開するときに送る If-Range の値。弱い ETag は If-Range に使えないため更新日時で代用する
+    pub fn if_range(&self) -> Option<&str> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_deref(),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut text = String::new();
+        if let Some(etag) = &self.etag {
+            text.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            text.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        fs::write(sidecar_path(path), text)
+    }
+}
+
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut meta = path.as_os_str().to_owned();
+    meta.push(".meta");
+    PathBuf::from(meta)
+}
+
+/// `path` のサイドカーを消す。元から無ければ何もしない
+pub fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(sidecar_path(path)) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,58 @@
+use std::{io, path::Path};
+
+/// --state-db: URL ごとのダウンロードの状態を組み込みの KV ストア (sled) に記録する
+///
+/// 値は状態を表す 1 語。状態が変わるたびにその URL の分だけを書き、ディスクへの書き出しは
+/// sled に任せる（一定間隔と閉じるときに書き出される）。次回の実行では完了済みの URL を飛ばし、
+/// それ以外は `.part` から再開する。受信済みのバイト数は記録せず、`.part` の大きさから求める。
+pub struct StateDb {
+    db: sled::Db,
+}
+
+/// 記録するダウンロードの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Downloading, // 始めたがまだ終わっていない（中断した場合もこのまま残る）
+    Done,
+    Failed,
+}
+
+impl Status {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Downloading => "downloading",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+
+    fn parse(text: &str) -> Option<Self> {
+        match text {
+            "downloading" => Some(Self::Downloading),
+            "done" => Some(Self::Done),
+            "failed" => Some(Self::Failed),
+            _ => None,
+        }
+    }
+}
+
+impl StateDb {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let db = sled::open(path).map_err(io::Error::other)?;
+        Ok(Self { db })
+    }
+
+    /// `url` の記録。無い場合や読めない場合は None
+    pub fn get(&self, url: &str) -> io::Result<Option<Status>> {
+        let value = self.db.get(url).map_err(io::Error::other)?;
+        Ok(value.and_then(|value| Status::parse(&String::from_utf8_lossy(&value))))
+    }
+
+    /// `url` の記録を書き換える
+    pub fn put(&self, url: &str, status: Status) -> io::Result<()> {
+        self.db
+            .insert(url, status.as_str())
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::mpsc,
+    thread,
+};
+
+/// --tar-stdout: 保存し終えたファイルを tar のエントリとして書き出す
+///
+/// tar のヘッダーには先にサイズが要るので、いったん作業ディレクトリに保存してから書き出し、
+/// 書き出したファイルは消す。エントリ名は作業ディレクトリからの相対パスになる。
+pub struct TarStream<W: Write> {
+    builder: tar::Builder<W>,
+    staging: Staging,
+}
+
+/// 作業ディレクトリ。エラーで途中で抜けても、ドロップで中身ごと消す
+struct Staging(PathBuf);
+
+/// TarStream を別スレッドで動かす。標準出力の読み手が遅くても、書き出しを頼む側は待たない
+pub struct TarWriter<W: Write + Send + 'static> {
+    paths: Option<mpsc::Sender<PathBuf>>,
+    thread: Option<thread::JoinHandle<io::Result<W>>>,
+}
+
+impl<W: Write> TarStream<W> {
+    pub fn new(writer: W, staging: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&staging)?;
+        Ok(Self {
+            builder: tar::Builder::new(writer),
+            staging: Staging(staging),
+        })
+    }
+
+    pub fn append(&mut self, path: &Path) -> io::Result<()> {
+        let name = path.strip_prefix(&self.staging.0).unwrap_or(path);
+        self.builder.append_path_with_name(path, name)?;
+        fs::remove_file(path)
+    }
+
+    /// アーカイブの終端を書き、作業ディレクトリを片付ける
+    pub fn finish(self) -> io::Result<W> {
+        let mut writer = self.builder.into_inner()?;
+        writer.flush()?;
+        fs::remove_dir_all(&self.staging.0)?;
+        Ok(writer)
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+impl<W: Write + Send + 'static> TarWriter<W> {
+    pub fn spawn(mut stream: TarStream<W>) -> Self {
+        let (paths, rx) = mpsc::channel::<PathBuf>();
+        let thread = thread::spawn(move || {
+            for path in rx {
+                stream.append(&path)?;
+            }
+            stream.finish()
+        });
+        Self {
+            paths: Some(paths),
+            thread: Some(thread),
+        }
+    }
+
+    /// `path` の書き出しを頼む。書き出しが失敗して止まっていれば、その理由を返す
+    pub fn append(&mut self, path: PathBuf) -> io::Result<()> {
+        if self
+            .paths
+            .as_ref()
+            .is_some_and(|paths| paths.send(path).is_ok())
+        {
+            return Ok(());
+        }
+        self.join()?;
+        Err(io::Error::other("tar の書き出しは終了しています"))
+    }
+
+    /// 頼んだ分を書き終えるまで待ってアーカイブを閉じ、書き出し先を返す
+    pub fn finish(mut self) -> io::Result<W> {
+        self.join()?
+            .ok_or_else(|| io::Error::other("tar の書き出しは終了しています"))
+    }
+
+    fn join(&mut self) -> io::Result<Option<W>> {
+        self.paths = None;
+        let Some(thread) = self.thread.take() else {
+            return Ok(None);
+        };
+        thread
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("tar を書き出すスレッドが異常終了しました")))
+            .map(Some)
+    }
+}
+
+impl<W: Write + Send + 'static> Drop for TarWriter<W> {
+    /// 途中で抜けた場合もスレッドの終了を待ち、作業ディレクトリを片付けさせる
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
@@ -0,0 +1,144 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// `--output-template` の保存先パスのテンプレート
+///
+/// 使えるプレースホルダー:
+/// - `{host}`: URL のホスト名（ポート指定があれば `host_port`）
+/// - `{date}`: 実行した日付（UTC, `YYYY-MM-DD`）
+/// - `{index}`: 1 から始まるダウンロードの順番
+/// - `{name}`: URL から決めた元のファイル名
+///
+/// `{{` と `}}` はそれぞれリテラルの `{` と `}` になる。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Host,
+    Date,
+    Index,
+    Name,
+}
+
+/// テンプレートに埋め込む 1 ダウンロード分の値
+pub struct TemplateValues<'a> {
+    pub host: &'a str,
+    pub date: &'a str,
+    pub index: usize,
+    pub name: &'a str,
+}
+
+impl OutputTemplate {
+    /// テンプレートを解釈する。未知のプレースホルダーはエラーにする
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("閉じ括弧 '}}' がありません: {s}"))?;
+                    let part = match &rest[..end] {
+                        "host" => Part::Host,
+                        "date" => Part::Date,
+                        "index" => Part::Index,
+                        "name" => Part::Name,
+                        other => {
+                            return Err(format!(
+                                "不明なプレースホルダーです: {{{other}}}（使えるのは {{host}} {{date}} {{index}} {{name}}）"
+                            ))
+                        }
+                    };
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(part);
+                    chars = rest[end + 1..].chars();
+                }
+                '}' => return Err(format!("対応する '{{' のない '}}' があります: {s}")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        if !parts.contains(&Part::Name) && !parts.contains(&Part::Index) {
+            return Err(
+                "ファイル名が重複しないよう {name} か {index} を含めてください".to_string(),
+            );
+        }
+        Ok(Self { parts })
+    }
+
+    /// `/` 区切りの相対パスを返す。各要素の無害化は呼び出し側で行う
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Host => out.push_str(values.host),
+                Part::Date => out.push_str(values.date),
+                Part::Index => out.push_str(&values.index.to_string()),
+                Part::Name => out.push_str(values.name),
+            }
+        }
+        out
+    }
+}
+
+/// 今日の日付 (UTC) を `YYYY-MM-DD` で返す
+pub fn today() -> String {
+    let (year, month, day) = civil_from_days((unix_secs() / 86_400) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// 現在時刻 (UTC) を RFC 3339 形式 (`YYYY-MM-DDTHH:MM:SSZ`) で返す
+pub fn timestamp() -> String {
+    let secs = unix_secs();
+    let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+    let time = secs % 86_400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60
+    )
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// 1970-01-01 からの日数をグレゴリオ暦の年月日にする (Howard Hinnant のアルゴリズム)
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,63 @@
+use std::path::Path;
+
+use crate::audit::json_string;
+
+/// 1 ファイル分のダウンロード結果
+pub enum Outcome {
+    Done,
+    Skipped(String), // 理由
+    Failed(String),  // エラー
+}
+
+pub struct FileResult<'a> {
+    pub url: &'a str,
+    pub path: &'a Path,
+    pub outcome: Option<&'a Outcome>, // 終わる前に中断した場合は None
+    pub sha256: Option<&'a str>,
+}
+
+/// --webhook に POST する集計の JSON
+///
+/// `{"ok":1,"failed":0,"skipped":0,"bytes":123,"secs":1.5,"files":[{"url":"...","path":"...","status":"done","sha256":"..."}]}` の形。
+/// status は done / skipped / failed / incomplete のいずれかで、skipped と failed には理由の "reason" が付く。
+pub fn summary_json(files: &[FileResult], bytes: u64, secs: f64) -> String {
+    let count = |f: fn(&Outcome) -> bool| {
+        files
+            .iter()
+            .filter(|file| file.outcome.is_some_and(f))
+            .count()
+    };
+    let entries: Vec<String> = files.iter().map(file_json).collect();
+    format!(
+        "{{\"ok\":{},\"failed\":{},\"skipped\":{},\"bytes\":{},\"secs\":{:.1},\"files\":[{}]}}",
+        count(|outcome| matches!(outcome, Outcome::Done)),
+        count(|outcome| matches!(outcome, Outcome::Failed(_))),
+        count(|outcome| matches!(outcome, Outcome::Skipped(_))),
+        bytes,
+        secs,
+        entries.join(",")
+    )
+}
+
+fn file_json(file: &FileResult) -> String {
+    let (status, reason) = match file.outcome {
+        Some(Outcome::Done) => ("done", None),
+        Some(Outcome::Skipped(reason)) => ("skipped", Some(reason)),
+        Some(Outcome::Failed(error)) => ("failed", Some(error)),
+        None => ("incomplete", None),
+    };
+    let mut json = format!(
+        "{{\"url\":{},\"path\":{},\"status\":\"{}\"",
+        json_string(file.url),
+        json_string(&file.path.to_string_lossy()),
+        status
+    );
+    if let Some(reason) = reason {
+        json.push_str(&format!(",\"reason\":{}", json_string(reason)));
+    }
+    if let Some(sha256) = file.sha256 {
+        json.push_str(&format!(",\"sha256\":{}", json_string(sha256)));
+    }
+    json.push('}');
+    json
+}
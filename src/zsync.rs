@@ -0,0 +1,299 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use sha2::{Digest, Sha256};
+
+/// --zsync / --repair で使うブロックハッシュのマニフェスト
+///
+/// テキスト形式で、ヘッダー行のあとにブロックごとの SHA-256 を 1 行ずつ並べる:
+///
+/// ```text
+/// length: 300000
+/// blocksize: 65536
+/// 3b5d...（先頭ブロックの SHA-256）
+/// ...
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub length: u64,
+    pub block_size: u64,
+    pub blocks: Vec<String>,
+}
+
+/// blocksize の上限。ブロック 1 つ分をまとめてメモリに読むため、大きすぎる指定は受け付けない
+pub const MAX_BLOCK_SIZE: u64 = 16 * 1024 * 1024;
+
+/// 出力ファイルを組み立てる単位。隣り合うブロックはまとめて 1 回で読む・取得する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    /// 手元のファイルの `offset` から `len` バイトをそのまま使う
+    Local { offset: u64, len: u64 },
+    /// リモートの `start` から `len` バイトを Range リクエストで取得する
+    Remote { start: u64, len: u64 },
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut length = None;
+        let mut block_size = None;
+        let mut blocks = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match line.split_once(": ") {
+                Some(("length", value)) => length = value.parse().ok(),
+                Some(("blocksize", value)) => {
+                    block_size = value.parse().ok().filter(|&size| size > 0)
+                }
+                _ if line.len() == 64 && line.chars().all(|c| c.is_ascii_hexdigit()) => {
+                    blocks.push(line.to_ascii_lowercase());
+                }
+                _ => return Err(format!("マニフェストの行を解釈できません: {line}")),
+            }
+        }
+        let length: u64 = length.ok_or("マニフェストに length がありません")?;
+        let block_size: u64 = block_size.ok_or("マニフェストに正しい blocksize がありません")?;
+        if block_size > MAX_BLOCK_SIZE {
+            return Err(format!(
+                "blocksize が大きすぎます ({} バイト, 上限 {} バイト)",
+                block_size, MAX_BLOCK_SIZE
+            ));
+        }
+        if blocks.len() as u64 != length.div_ceil(block_size) {
+            return Err(format!(
+                "ブロック数が length と一致しません ({} 個, 期待値 {} 個)",
+                blocks.len(),
+                length.div_ceil(block_size)
+            ));
+        }
+        Ok(Self {
+            length,
+            block_size,
+            blocks,
+        })
+    }
+
+    /// `index` 番目のブロックの長さ（最後のブロックだけ短いことがある）
+    pub fn block_len(&self, index: usize) -> u64 {
+        let start = index as u64 * self.block_size;
+        self.block_size.min(self.length - start)
+    }
+
+    /// 手元のファイルにあるブロックを探し、出力の組み立て手順を返す
+    ///
+    /// 手元のファイルはブロック境界ごとにハッシュするので、ブロック単位でずれた
+    /// 内容は再利用できるが、境界をまたいでずれた内容は取得し直しになる。
+    pub fn segments(&self, local: Option<&Path>) -> io::Result<Vec<Segment>> {
+        let local_blocks = match local {
+            Some(path) => hash_local_blocks(path, self.block_size)?,
+            None => HashMap::new(),
+        };
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for (index, hash) in self.blocks.iter().enumerate() {
+            let start = index as u64 * self.block_size;
+            let len = self.block_len(index);
+            let segment = match local_blocks.get(&(hash.clone(), len)) {
+                Some(&offset) => Segment::Local { offset, len },
+                None => Segment::Remote { start, len },
+            };
+            push_segment(&mut segments, segment);
+        }
+        Ok(segments)
+    }
+
+    /// --repair: 手元のファイルをブロックごとに同じ位置のハッシュと照合し、組み立て手順と
+    /// 取得し直すブロックの番号を返す。足りないブロックも取得し直す対象になる
+    pub fn repair_segments(&self, local: &Path) -> io::Result<(Vec<Segment>, Vec<usize>)> {
+        let mut file = File::open(local)?;
+        let mut buf = vec![0u8; self.block_size as usize];
+        let mut segments = Vec::new();
+        let mut broken = Vec::new();
+        for (index, hash) in self.blocks.iter().enumerate() {
+            let start = index as u64 * self.block_size;
+            let len = self.block_len(index);
+            let n = read_full(&mut file, &mut buf[..len as usize])?;
+            let segment = if n as u64 == len && hash_hex(&buf[..n]) == *hash {
+                Segment::Local { offset: start, len }
+            } else {
+                broken.push(index);
+                Segment::Remote { start, len }
+            };
+            push_segment(&mut segments, segment);
+        }
+        Ok((segments, broken))
+    }
+}
+
+/// 直前の区間と続いていればまとめ、そうでなければ新しい区間として足す
+fn push_segment(segments: &mut Vec<Segment>, segment: Segment) {
+    match (segments.last_mut(), segment) {
+        (Some(Segment::Local { offset, len: run }), Segment::Local { offset: next, len })
+            if *offset + *run == next =>
+        {
+            *run += len
+        }
+        (Some(Segment::Remote { len: run, .. }), Segment::Remote { len, .. }) => *run += len,
+        _ => segments.push(segment),
+    }
+}
+
+/// ブロック番号の一覧を「0, 3-5, 9」のように連続する範囲をまとめて表す
+pub fn format_blocks(blocks: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for &block in blocks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == block => *end = block,
+            _ => ranges.push((block, block)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|&(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// データの SHA-256 を 16 進文字列で返す
+pub fn hash_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// ブロック境界ごとのハッシュとその位置。同じ内容のブロックは最初の位置を使う
+fn hash_local_blocks(path: &Path, block_size: u64) -> io::Result<HashMap<(String, u64), u64>> {
+    let mut blocks = HashMap::new();
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(blocks),
+        Err(e) => return Err(e),
+    };
+    let mut buf = vec![0u8; block_size as usize];
+    let mut offset = 0;
+    loop {
+        let n = read_full(&mut file, &mut buf)?;
+        if n == 0 {
+            break;
+        }
+        blocks
+            .entry((hash_hex(&buf[..n]), n as u64))
+            .or_insert(offset);
+        offset += n as u64;
+    }
+    Ok(blocks)
+}
+
+/// EOF に達するまで `buf` を埋め、読めたバイト数を返す
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    const DATA: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn manifest_text(data: &[u8], block_size: usize) -> String {
+        let mut text = format!("length: {}\nblocksize: {}\n", data.len(), block_size);
+        for block in data.chunks(block_size) {
+            text.push_str(&format!("{}\n", hash_hex(block)));
+        }
+        text
+    }
+
+    fn local_file(name: &str, data: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("cli-tools-{}-zsync-{name}", std::process::id()));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn parse_reads_header_and_blocks() {
+        let manifest = Manifest::parse(&manifest_text(DATA, 8)).unwrap();
+        assert_eq!(manifest.length, 36);
+        assert_eq!(manifest.block_size, 8);
+        assert_eq!(manifest.blocks.len(), 5);
+        assert_eq!(manifest.block_len(0), 8);
+        assert_eq!(manifest.block_len(4), 4);
+    }
+
+    #[test]
+    fn parse_rejects_broken_manifests() {
+        let hash = hash_hex(DATA);
+        assert!(Manifest::parse(&format!("blocksize: 64\n{hash}\n")).is_err());
+        assert!(Manifest::parse(&format!("length: 36\nblocksize: 0\n{hash}\n")).is_err());
+        assert!(Manifest::parse(&format!("length: 36\nblocksize: 8\n{hash}\n")).is_err());
+        assert!(Manifest::parse(&format!("length: 36\nblocksize: 64\nsize 36\n{hash}\n")).is_err());
+        let huge = format!("length: 36\nblocksize: {}\n{hash}\n", MAX_BLOCK_SIZE + 1);
+        assert!(Manifest::parse(&huge)
+            .unwrap_err()
+            .contains("blocksize が大きすぎます"));
+    }
+
+    #[test]
+    fn segments_reuse_moved_blocks() {
+        let manifest = Manifest::parse(&manifest_text(DATA, 8)).unwrap();
+        assert_eq!(
+            manifest.segments(None).unwrap(),
+            [Segment::Remote { start: 0, len: 36 }]
+        );
+
+        // 旧版ではブロック 0, 1 が 1 ブロック後ろにずれ、ブロック 3 が変わっている
+        let mut old = b"XXXXXXXX".to_vec();
+        old.extend_from_slice(&DATA[..24]);
+        old.extend_from_slice(b"YYYYYYYY");
+        old.extend_from_slice(&DATA[32..]);
+        let local = local_file("segments", &old);
+        assert_eq!(
+            manifest.segments(Some(&local)).unwrap(),
+            [
+                Segment::Local { offset: 8, len: 24 },
+                Segment::Remote { start: 24, len: 8 },
+                Segment::Local { offset: 40, len: 4 },
+            ]
+        );
+        fs::remove_file(local).unwrap();
+    }
+
+    #[test]
+    fn repair_segments_compare_in_place() {
+        let manifest = Manifest::parse(&manifest_text(DATA, 8)).unwrap();
+        let mut broken = DATA[..30].to_vec();
+        broken[9] = b'X';
+        let local = local_file("repair", &broken);
+        let (segments, blocks) = manifest.repair_segments(&local).unwrap();
+        assert_eq!(
+            segments,
+            [
+                Segment::Local { offset: 0, len: 8 },
+                Segment::Remote { start: 8, len: 8 },
+                Segment::Local { offset: 16, len: 8 },
+                Segment::Remote { start: 24, len: 12 },
+            ]
+        );
+        assert_eq!(blocks, [1, 3, 4]);
+        assert_eq!(format_blocks(&blocks), "1, 3-4");
+        fs::remove_file(local).unwrap();
+    }
+}